@@ -0,0 +1,49 @@
+//! Receiver of `flash_loan` callbacks for the tests, see `flashloan::receiver`
+use anchor_lang::prelude::*;
+use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+
+declare_id!("AL9nsVtoej6dkk5Q5wZF5gf9sfpX7JURRvGRVkAK7YuL");
+
+#[program]
+pub mod flashloan_receiver {
+    use super::*;
+
+    /// Pays `amount + fee` back to the pool, or nothing when `data` isn't empty
+    pub fn on_flash_loan(
+        ctx: Context<OnFlashLoan>,
+        _initiator: Pubkey,
+        _token_mint: Pubkey,
+        amount: u64,
+        fee: u64,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        if !data.is_empty() {
+            return Ok(());
+        }
+
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.receiver_token.to_account_info(),
+                to: ctx.accounts.pool_token.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        );
+
+        token::transfer(transfer_ctx, amount + fee)
+    }
+}
+
+#[derive(Accounts)]
+pub struct OnFlashLoan<'info> {
+    #[account(mut)]
+    pub receiver_token: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub pool_token: Account<'info, TokenAccount>,
+
+    /// Owner of the receiver token account, signs the `flash_loan` transaction
+    pub owner: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
@@ -4,25 +4,35 @@ use anchor_lang::prelude::*;
 use crate::FlashLoanError;
 use std::convert::TryFrom;
 
+/// Virtual liquidity added to both the pool value and the lp supply.
+/// The first deposit still mints 1:1, but a donation to the pool mostly accrues to the virtual shares,
+/// so inflating the share price to round later depositors down to zero costs the attacker
+/// more than the depositors lose
+pub const VIRTUAL_LIQUIDITY: u64 = 1_000;
+
 /// calculate amount*numerator/denominator
 /// as value  = shares * share_price where share_price=total_value/total_shares
 /// or shares = amount_value / share_price where share_price=total_value/total_shares
 ///     => shares = amount_value * 1/share_price where 1/share_price=total_shares/total_value
-pub fn proportional(amount: u64, numerator: u64, denominator: u64) -> Result<u64> {
+pub fn proportional(amount: u64, numerator: u128, denominator: u128) -> Result<u64> {
     if denominator == 0 {
         return Ok(amount);
     }
-    u64::try_from((amount as u128) * (numerator as u128) / (denominator as u128))
+    u64::try_from((amount as u128) * numerator / denominator)
         .map_err(|_| error!(FlashLoanError::CalculationFailure))
 }
 
-#[inline] //alias for proportional
+#[inline]
+fn with_virtual_liquidity(total: u64) -> u128 {
+    total as u128 + VIRTUAL_LIQUIDITY as u128
+}
+
 pub fn value_from_shares(
     shares: u64,
     total_value: u64,
     total_shares: u64,
 ) -> Result<u64> {
-    proportional(shares, total_value, total_shares)
+    proportional(shares, with_virtual_liquidity(total_value), with_virtual_liquidity(total_shares))
 }
 
 pub fn shares_from_value(
@@ -30,10 +40,109 @@ pub fn shares_from_value(
     total_value: u64,
     total_shares: u64,
 ) -> Result<u64> {
-    if total_shares == 0 {
-        //no shares minted yet / First mint
-        Ok(value)
-    } else {
-        proportional(value, total_shares, total_value)
+    proportional(value, with_virtual_liquidity(total_shares), with_virtual_liquidity(total_value))
+}
+
+/// Value held by pool_token on behalf of the liquidity providers,
+/// accrued protocol fees are excluded from the share price
+pub fn lp_value(pool_balance: u64, protocol_fees: u64) -> Result<u64> {
+    pool_balance
+        .checked_sub(protocol_fees)
+        .ok_or_else(|| error!(FlashLoanError::CalculationFailure))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Pool state after an attacker deposits `attacker_value` into an empty pool and donates `donation`
+    fn inflated_pool(attacker_value: u64, donation: u64) -> (u64, u64) {
+        let attacker_shares = shares_from_value(attacker_value, 0, 0).unwrap();
+        (attacker_value + donation, attacker_shares)
+    }
+
+    #[test]
+    fn first_deposit_mints_one_to_one() {
+        assert_eq!(shares_from_value(1, 0, 0).unwrap(), 1);
+        assert_eq!(shares_from_value(1_000_000, 0, 0).unwrap(), 1_000_000);
+        assert_eq!(shares_from_value(u64::MAX, 0, 0).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn withdraw_everything_at_par() {
+        assert_eq!(value_from_shares(1_000_000, 1_000_000, 1_000_000).unwrap(), 1_000_000);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn classic_inflation_attack_fails() {
+        // 1 unit deposit followed by a donation of 10k units, the victim deposits 10k units as well
+        let (total_value, total_shares) = inflated_pool(1, 10_000);
+        let victim_shares = shares_from_value(10_000, total_value, total_shares).unwrap();
+
+        assert!(victim_shares > 0);
+        let victim_value = value_from_shares(
+            victim_shares,
+            total_value + 10_000,
+            total_shares + victim_shares,
+        ).unwrap();
+        assert!(victim_value >= 10_000 * 9 / 10);
+    }
+
+    proptest! {
+        #[test]
+        fn small_depositors_are_not_zeroed_out(
+            attacker_value in 1..=1_000_000_u64,
+            value in 1..=1_000_000_u64,
+            donation in 0..=u32::MAX as u64,
+        ) {
+            let (total_value, total_shares) = inflated_pool(attacker_value, donation);
+            let shares = shares_from_value(value, total_value, total_shares).unwrap();
+
+            // zeroing a depositor takes a donation of at least (value - 1) * (attacker_value + VIRTUAL_LIQUIDITY)
+            let zeroing_donation = (value as u128 - 1) * (attacker_value as u128 + VIRTUAL_LIQUIDITY as u128);
+            if (donation as u128) < zeroing_donation {
+                prop_assert!(shares > 0);
+            }
+        }
+
+        #[test]
+        fn inflation_attack_is_not_profitable(
+            attacker_value in 1..=1_000_000_u64,
+            value in 1..=1_000_000_u64,
+            donation in 0..=u32::MAX as u64,
+        ) {
+            let (total_value, total_shares) = inflated_pool(attacker_value, donation);
+            let shares = shares_from_value(value, total_value, total_shares).unwrap();
+
+            let attacker_value_after = value_from_shares(
+                total_shares,
+                total_value + value,
+                total_shares + shares,
+            ).unwrap();
+
+            // the attacker never gets back more than deposited and donated,
+            // and pays more than the depositor loses whenever the depositor is zeroed out
+            let spent = attacker_value as u128 + donation as u128;
+            prop_assert!(attacker_value_after as u128 <= spent);
+            if shares == 0 && value > 1 {
+                prop_assert!(spent - attacker_value_after as u128 > value as u128);
+            }
+        }
+
+        #[test]
+        fn deposit_and_withdraw_never_gains(
+            total_value in 0..=u64::MAX / 2,
+            total_shares in 0..=u64::MAX / 2,
+            value in 0..=u64::MAX / 4,
+        ) {
+            let shares = shares_from_value(value, total_value, total_shares);
+            prop_assume!(shares.is_ok());
+            let shares = shares.unwrap();
+            prop_assume!(total_shares.checked_add(shares).is_some());
+
+            let withdrawn = value_from_shares(shares, total_value + value, total_shares + shares).unwrap();
+            prop_assert!(withdrawn <= value);
+        }
+    }
+}
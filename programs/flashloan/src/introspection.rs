@@ -0,0 +1,480 @@
+//! Instruction introspection
+//!
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::sysvar::instructions;
+use crate::FlashLoanError;
+
+/// Length of the anchor instruction discriminator, Sha256("global:<rust-identifier>")[..8]
+pub const DISCRIMINATOR_LEN: usize = 8;
+
+/// Positions of the accounts within a `Repay` instruction
+pub const REPAY_FLASHLOAN_INDEX: usize = 0;
+pub const REPAY_POOL_INDEX: usize = 1;
+pub const REPAY_POOL_TOKEN_INDEX: usize = 2;
+
+/// Positions of the accounts within a `RepayMany` instruction, the pool groups follow the fixed accounts
+pub const REPAY_MANY_FLASHLOAN_INDEX: usize = 0;
+pub const REPAY_MANY_FIXED_ACCOUNTS: usize = 4;
+
+/// Returns the index of the currently executing top-level instruction and the instruction itself
+pub fn load_current_instruction(ixns: &AccountInfo) -> Result<(usize, Instruction)> {
+    let current_idx = instructions::load_current_index_checked(ixns)? as usize;
+    let current_ixn = load_instruction(current_idx, ixns)?
+        .ok_or_else(|| error!(FlashLoanError::InvalidInstructionSysvar))?;
+
+    Ok((current_idx, current_ixn))
+}
+
+/// Number of top-level instructions in the transaction, stored as u16 at the start of the sysvar
+pub fn instruction_count(ixns: &AccountInfo) -> Result<usize> {
+    let data = ixns.try_borrow_data()?;
+    let count = data.get(..2)
+        .ok_or_else(|| error!(FlashLoanError::InvalidInstructionSysvar))?;
+
+    Ok(u16::from_le_bytes([count[0], count[1]]) as usize)
+}
+
+/// Loads the top-level instruction at `idx`, `None` once past the last instruction
+pub fn load_instruction(idx: usize, ixns: &AccountInfo) -> Result<Option<Instruction>> {
+    if idx >= instruction_count(ixns)? {
+        return Ok(None);
+    }
+
+    // the index is in range, any failure means the sysvar data is malformed
+    instructions::load_instruction_at_checked(idx, ixns)
+        .map(Some)
+        .map_err(|_| error!(FlashLoanError::InvalidInstructionSysvar))
+}
+
+/// Checks the instruction targets `program_id` and starts with `discriminator`,
+/// instructions with data shorter than a discriminator never match
+pub fn is_instruction(ixn: &Instruction, program_id: &Pubkey, discriminator: &[u8]) -> bool {
+    ixn.program_id == *program_id
+        && ixn.data.get(..DISCRIMINATOR_LEN) == discriminator.get(..DISCRIMINATOR_LEN)
+}
+
+/// Checks the instruction account at `idx` is `key`, missing accounts never match
+pub fn has_account_at(ixn: &Instruction, idx: usize, key: &Pubkey) -> bool {
+    ixn.accounts.get(idx).map(|meta| meta.pubkey == *key).unwrap_or(false)
+}
+
+/// Checks `key` is one of the instruction accounts
+pub fn has_account(ixn: &Instruction, key: &Pubkey) -> bool {
+    ixn.accounts.iter().any(|meta| meta.pubkey == *key)
+}
+
+/// Looks for a top-level `Repay` after `current_idx` towards the same flashloan, pool and pool token,
+/// any other instruction in between is skipped. Returns the index of the matching repay.
+pub fn find_repay(
+    ixns: &AccountInfo,
+    current_idx: usize,
+    program_id: &Pubkey,
+    flashloan: &Pubkey,
+    pool: &Pubkey,
+    pool_token: &Pubkey,
+) -> Result<usize> {
+    let mut idx = current_idx + 1;
+
+    while let Some(ixn) = load_instruction(idx, ixns)? {
+        if is_repay(&ixn, program_id, flashloan, pool, pool_token) {
+            return Ok(idx);
+        }
+        idx += 1;
+    }
+
+    Err(error!(FlashLoanError::NoRepay))
+}
+
+/// Checks `ixn` is a `Repay` of this program towards the given flashloan, pool and pool token
+fn is_repay(ixn: &Instruction, program_id: &Pubkey, flashloan: &Pubkey, pool: &Pubkey, pool_token: &Pubkey) -> bool {
+    is_instruction(ixn, program_id, &crate::Repay::SIGHASH)
+        && has_account_at(ixn, REPAY_FLASHLOAN_INDEX, flashloan)
+        && has_account_at(ixn, REPAY_POOL_INDEX, pool)
+        && has_account_at(ixn, REPAY_POOL_TOKEN_INDEX, pool_token)
+}
+
+/// Looks for a top-level instruction after `current_idx`, the borrowing one excluded, that repays a
+/// cpi borrow of a trusted `caller`: either a `Repay` towards the same flashloan, pool and pool token,
+/// or an instruction of the caller passing this program, the instructions sysvar and those accounts
+/// to repay through cpi. Returns the index of the matching instruction.
+pub fn find_caller_repay(
+    ixns: &AccountInfo,
+    current_idx: usize,
+    program_id: &Pubkey,
+    caller: &Pubkey,
+    flashloan: &Pubkey,
+    pool: &Pubkey,
+    pool_token: &Pubkey,
+) -> Result<usize> {
+    let mut idx = current_idx + 1;
+
+    while let Some(ixn) = load_instruction(idx, ixns)? {
+        if is_repay(&ixn, program_id, flashloan, pool, pool_token) {
+            return Ok(idx);
+        }
+        if ixn.program_id == *caller
+            && has_account(&ixn, program_id)
+            && has_account(&ixn, &instructions::ID)
+            && has_account(&ixn, flashloan)
+            && has_account(&ixn, pool)
+            && has_account(&ixn, pool_token) {
+            return Ok(idx);
+        }
+        idx += 1;
+    }
+
+    Err(error!(FlashLoanError::NoRepay))
+}
+
+/// Looks for a top-level `RepayMany` after `current_idx` covering exactly the given (pool, pool_token)
+/// pairs in the same order. Returns the index of the matching repay.
+pub fn find_repay_many(
+    ixns: &AccountInfo,
+    current_idx: usize,
+    program_id: &Pubkey,
+    flashloan: &Pubkey,
+    pools: &[(Pubkey, Pubkey)],
+) -> Result<usize> {
+    let mut idx = current_idx + 1;
+
+    while let Some(ixn) = load_instruction(idx, ixns)? {
+        if is_instruction(&ixn, program_id, &crate::RepayMany::SIGHASH)
+            && has_account_at(&ixn, REPAY_MANY_FLASHLOAN_INDEX, flashloan)
+            && ixn.accounts.len() == REPAY_MANY_FIXED_ACCOUNTS + pools.len() * crate::BATCH_LEG_ACCOUNTS
+            && pools.iter().enumerate().all(|(i, (pool, pool_token))| {
+                let leg = REPAY_MANY_FIXED_ACCOUNTS + i * crate::BATCH_LEG_ACCOUNTS;
+                has_account_at(&ixn, leg, pool) && has_account_at(&ixn, leg + 1, pool_token)
+            }) {
+            return Ok(idx);
+        }
+        idx += 1;
+    }
+
+    Err(error!(FlashLoanError::NoRepay))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::solana_program::instruction::AccountMeta;
+    use anchor_lang::solana_program::message::Message;
+
+    struct Keys {
+        flashloan: Pubkey,
+        pool: Pubkey,
+        pool_token: Pubkey,
+    }
+
+    impl Keys {
+        fn new() -> Self {
+            Self {
+                flashloan: Pubkey::new_unique(),
+                pool: Pubkey::new_unique(),
+                pool_token: Pubkey::new_unique(),
+            }
+        }
+    }
+
+    fn borrow_ixn(keys: &Keys) -> Instruction {
+        Instruction {
+            program_id: crate::ID,
+            accounts: vec![
+                AccountMeta::new_readonly(keys.flashloan, false),
+                AccountMeta::new_readonly(Pubkey::new_unique(), false),
+                AccountMeta::new(keys.pool, false),
+                AccountMeta::new(keys.pool_token, false),
+            ],
+            data: vec![1; 16],
+        }
+    }
+
+    fn repay_ixn(keys: &Keys) -> Instruction {
+        let mut data = crate::Repay::SIGHASH[..DISCRIMINATOR_LEN].to_vec();
+        data.extend_from_slice(&100_u64.to_le_bytes());
+
+        Instruction {
+            program_id: crate::ID,
+            accounts: vec![
+                AccountMeta::new_readonly(keys.flashloan, false),
+                AccountMeta::new(keys.pool, false),
+                AccountMeta::new(keys.pool_token, false),
+                AccountMeta::new(Pubkey::new_unique(), false),
+            ],
+            data,
+        }
+    }
+
+    fn repay_many_ixn(keys: &Keys, pools: &[(Pubkey, Pubkey)]) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new_readonly(keys.flashloan, false),
+            AccountMeta::new_readonly(Pubkey::new_unique(), false),
+            AccountMeta::new_readonly(instructions::ID, false),
+            AccountMeta::new_readonly(Pubkey::new_unique(), false),
+        ];
+        for (pool, pool_token) in pools {
+            accounts.push(AccountMeta::new(*pool, false));
+            accounts.push(AccountMeta::new(*pool_token, false));
+            accounts.push(AccountMeta::new(Pubkey::new_unique(), false));
+        }
+
+        Instruction {
+            program_id: crate::ID,
+            accounts,
+            data: crate::RepayMany::SIGHASH[..DISCRIMINATOR_LEN].to_vec(),
+        }
+    }
+
+    fn short_data_ixn(data: Vec<u8>) -> Instruction {
+        Instruction {
+            program_id: Pubkey::new_unique(),
+            accounts: vec![],
+            data,
+        }
+    }
+
+    /// Serializes instructions the way the runtime lays out the instructions sysvar
+    fn sysvar_data(ixns: &[Instruction], current_idx: u16) -> Vec<u8> {
+        let message = Message::new(ixns, None);
+        let mut data = message.serialize_instructions(false);
+        data.extend_from_slice(&current_idx.to_le_bytes());
+        data
+    }
+
+    fn scan(data: &mut [u8], keys: &Keys) -> Result<usize> {
+        let key = instructions::ID;
+        let owner = Pubkey::default();
+        let mut lamports = 0;
+        let info = AccountInfo::new(&key, false, false, &mut lamports, data, &owner, false, 0);
+
+        let (current_idx, current_ixn) = load_current_instruction(&info)?;
+        assert_eq!(current_ixn.program_id, crate::ID);
+
+        find_repay(&info, current_idx, &crate::ID, &keys.flashloan, &keys.pool, &keys.pool_token)
+    }
+
+    fn scan_many(data: &mut [u8], keys: &Keys, pools: &[(Pubkey, Pubkey)]) -> Result<usize> {
+        let key = instructions::ID;
+        let owner = Pubkey::default();
+        let mut lamports = 0;
+        let info = AccountInfo::new(&key, false, false, &mut lamports, data, &owner, false, 0);
+
+        let (current_idx, _) = load_current_instruction(&info)?;
+
+        find_repay_many(&info, current_idx, &crate::ID, &keys.flashloan, pools)
+    }
+
+    fn error_code(err: Error) -> u32 {
+        match err {
+            Error::AnchorError(err) => err.error_code_number,
+            Error::ProgramError(err) => panic!("unexpected program error {}", err),
+        }
+    }
+
+    fn assert_error(result: Result<usize>, expected: FlashLoanError) {
+        assert_eq!(error_code(result.unwrap_err()), u32::from(expected));
+    }
+
+    #[test]
+    fn finds_adjacent_repay() {
+        let keys = Keys::new();
+        let mut data = sysvar_data(&[borrow_ixn(&keys), repay_ixn(&keys)], 0);
+
+        assert_eq!(scan(&mut data, &keys).unwrap(), 1);
+    }
+
+    #[test]
+    fn skips_short_data_instructions() {
+        let keys = Keys::new();
+        let mut data = sysvar_data(
+            &[
+                short_data_ixn(vec![]),
+                borrow_ixn(&keys),
+                short_data_ixn(vec![]),
+                short_data_ixn(vec![2, 0, 0]),
+                short_data_ixn(b"memo".to_vec()),
+                repay_ixn(&keys),
+            ],
+            1,
+        );
+
+        assert_eq!(scan(&mut data, &keys).unwrap(), 5);
+    }
+
+    #[test]
+    fn ignores_repay_before_borrow() {
+        let keys = Keys::new();
+        let mut data = sysvar_data(&[repay_ixn(&keys), borrow_ixn(&keys)], 1);
+
+        assert_error(scan(&mut data, &keys), FlashLoanError::NoRepay);
+    }
+
+    #[test]
+    fn fails_without_repay() {
+        let keys = Keys::new();
+        let mut data = sysvar_data(&[borrow_ixn(&keys), short_data_ixn(vec![])], 0);
+
+        assert_error(scan(&mut data, &keys), FlashLoanError::NoRepay);
+    }
+
+    #[test]
+    fn ignores_repay_for_other_accounts() {
+        let keys = Keys::new();
+
+        let mut other_pool = repay_ixn(&keys);
+        other_pool.accounts[REPAY_POOL_INDEX].pubkey = Pubkey::new_unique();
+
+        let mut other_flashloan = repay_ixn(&keys);
+        other_flashloan.accounts[REPAY_FLASHLOAN_INDEX].pubkey = Pubkey::new_unique();
+
+        let mut other_pool_token = repay_ixn(&keys);
+        other_pool_token.accounts[REPAY_POOL_TOKEN_INDEX].pubkey = Pubkey::new_unique();
+
+        let mut data = sysvar_data(&[borrow_ixn(&keys), other_pool, other_flashloan, other_pool_token], 0);
+
+        assert_error(scan(&mut data, &keys), FlashLoanError::NoRepay);
+    }
+
+    #[test]
+    fn ignores_repay_for_other_program() {
+        let keys = Keys::new();
+
+        let mut other_program = repay_ixn(&keys);
+        other_program.program_id = Pubkey::new_unique();
+
+        let mut data = sysvar_data(&[borrow_ixn(&keys), other_program], 0);
+
+        assert_error(scan(&mut data, &keys), FlashLoanError::NoRepay);
+    }
+
+    #[test]
+    fn ignores_repay_with_missing_accounts() {
+        let keys = Keys::new();
+
+        let mut truncated = repay_ixn(&keys);
+        truncated.accounts.truncate(REPAY_POOL_INDEX + 1);
+
+        let mut data = sysvar_data(&[borrow_ixn(&keys), truncated], 0);
+
+        assert_error(scan(&mut data, &keys), FlashLoanError::NoRepay);
+    }
+
+    #[test]
+    fn fails_on_corrupted_sysvar() {
+        let keys = Keys::new();
+        let mut data = sysvar_data(&[borrow_ixn(&keys), repay_ixn(&keys)], 0);
+
+        // keep the instruction count and offsets, drop the repay body
+        let offset = u16::from_le_bytes([data[4], data[5]]) as usize;
+        let mut corrupted = data[..offset + 4].to_vec();
+        corrupted.extend_from_slice(&0_u16.to_le_bytes());
+
+        assert_error(scan(&mut corrupted, &keys), FlashLoanError::InvalidInstructionSysvar);
+        assert_eq!(scan(&mut data, &keys).unwrap(), 1);
+    }
+
+    #[test]
+    fn finds_repay_many_for_same_pools() {
+        let keys = Keys::new();
+        let pools = [
+            (keys.pool, keys.pool_token),
+            (Pubkey::new_unique(), Pubkey::new_unique()),
+        ];
+        let mut data = sysvar_data(
+            &[borrow_ixn(&keys), repay_ixn(&keys), short_data_ixn(vec![]), repay_many_ixn(&keys, &pools)],
+            0,
+        );
+
+        assert_eq!(scan_many(&mut data, &keys, &pools).unwrap(), 3);
+    }
+
+    #[test]
+    fn ignores_repay_many_for_other_pools() {
+        let keys = Keys::new();
+        let pools = [
+            (keys.pool, keys.pool_token),
+            (Pubkey::new_unique(), Pubkey::new_unique()),
+        ];
+        let reversed = [pools[1], pools[0]];
+        let mut data = sysvar_data(
+            &[
+                borrow_ixn(&keys),
+                repay_many_ixn(&keys, &reversed),
+                repay_many_ixn(&keys, &pools[..1]),
+            ],
+            0,
+        );
+
+        assert_error(scan_many(&mut data, &keys, &pools), FlashLoanError::NoRepay);
+    }
+
+    /// Instruction of a trusted `caller` able to repay through cpi
+    fn caller_ixn(keys: &Keys, caller: Pubkey) -> Instruction {
+        let mut accounts = repay_ixn(keys).accounts;
+        accounts.reverse();
+        accounts.push(AccountMeta::new_readonly(instructions::ID, false));
+        accounts.push(AccountMeta::new_readonly(crate::ID, false));
+
+        Instruction {
+            program_id: caller,
+            accounts,
+            data: vec![],
+        }
+    }
+
+    fn scan_caller(data: &mut [u8], keys: &Keys, caller: &Pubkey) -> Result<usize> {
+        let key = instructions::ID;
+        let owner = Pubkey::default();
+        let mut lamports = 0;
+        let info = AccountInfo::new(&key, false, false, &mut lamports, data, &owner, false, 0);
+
+        let (current_idx, _) = load_current_instruction(&info)?;
+
+        find_caller_repay(&info, current_idx, &crate::ID, caller, &keys.flashloan, &keys.pool, &keys.pool_token)
+    }
+
+    #[test]
+    fn borrowing_caller_instruction_is_no_repay() {
+        let keys = Keys::new();
+        let caller = Pubkey::new_unique();
+        let mut data = sysvar_data(&[short_data_ixn(vec![]), caller_ixn(&keys, caller)], 1);
+
+        assert_error(scan_caller(&mut data, &keys, &caller), FlashLoanError::NoRepay);
+    }
+
+    #[test]
+    fn finds_later_repay_of_caller() {
+        let keys = Keys::new();
+        let caller = Pubkey::new_unique();
+
+        let mut data = sysvar_data(&[caller_ixn(&keys, caller), borrow_ixn(&keys), caller_ixn(&keys, caller)], 0);
+        assert_eq!(scan_caller(&mut data, &keys, &caller).unwrap(), 2);
+    }
+
+    #[test]
+    fn finds_later_top_level_repay() {
+        let keys = Keys::new();
+        let caller = Pubkey::new_unique();
+
+        let mut data = sysvar_data(&[caller_ixn(&keys, caller), short_data_ixn(vec![]), repay_ixn(&keys)], 0);
+        assert_eq!(scan_caller(&mut data, &keys, &caller).unwrap(), 2);
+    }
+
+    #[test]
+    fn ignores_caller_instruction_unable_to_repay() {
+        let keys = Keys::new();
+        let caller = Pubkey::new_unique();
+
+        let mut without_program = caller_ixn(&keys, caller);
+        without_program.accounts.pop();
+
+        let mut without_pool = caller_ixn(&keys, caller);
+        without_pool.accounts.retain(|meta| meta.pubkey != keys.pool);
+
+        let mut other_caller = caller_ixn(&keys, caller);
+        other_caller.program_id = Pubkey::new_unique();
+
+        let mut data = sysvar_data(&[caller_ixn(&keys, caller), without_program, without_pool, other_caller], 0);
+        assert_error(scan_caller(&mut data, &keys, &caller), FlashLoanError::NoRepay);
+    }
+}
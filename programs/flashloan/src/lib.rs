@@ -1,55 +1,337 @@
+use std::convert::TryFrom;
 use anchor_lang::prelude::*;
 use anchor_lang::AccountDeserialize;
-use anchor_spl::token::{self, Mint, TokenAccount, MintTo, Burn, Transfer, Token};
+use anchor_spl::token::{self, Mint, TokenAccount, MintTo, Burn, Transfer, SetAuthority, Token};
+use anchor_lang::solana_program::program::invoke;
+use anchor_lang::solana_program::program_option::COption;
 use anchor_lang::solana_program::sysvar::instructions;
-use std::convert::TryInto;
 use sha2_const::Sha256;
+use spl_token::instruction::AuthorityType;
 
 mod calc;
+mod introspection;
+mod merkle;
+mod migration;
+pub mod receiver;
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
 #[program]
 pub mod flashloan {
-    use crate::calc::{shares_from_value, value_from_shares};
+    use crate::calc::{lp_value, shares_from_value, value_from_shares};
+    use crate::introspection::{find_caller_repay, find_repay, find_repay_many, load_current_instruction};
+    use crate::migration::{load_outdated_flashloan, load_outdated_pool, store};
+    use crate::receiver::{on_flash_loan_instruction, OnFlashLoanArgs};
     use super::*;
 
-    pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
+    pub fn initialize(ctx: Context<Initialize>, max_fee: u64) -> Result<()> {
+        let max_fee = Fee::from_rate(max_fee);
+        require!(max_fee <= Fee::MAX, FlashLoanError::MaxFeeTooHigh);
+
         let flashloan = &mut ctx.accounts.flashloan;
 
+        flashloan.version = FlashLoan::VERSION;
         flashloan.token_authority_bump = *ctx.bumps.get("token_authority").unwrap();
         flashloan.authority = ctx.accounts.authority.key();
+        flashloan.max_fee = max_fee;
+
+        Ok(())
+    }
+
+    /// Set the share of every loan fee going to the protocol and the owner of the treasury token accounts
+    pub fn set_protocol_fee(ctx: Context<SetProtocolFee>, protocol_fee: u64, treasury: Pubkey) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(None, &ctx.accounts.authority, ctx.remaining_accounts)?;
+
+        // the protocol share rounds down in favor of the liquidity providers
+        let protocol_fee = Fee::from_rate(protocol_fee).with_rounding(Rounding::Floor);
+        require!(protocol_fee <= Fee::MAX, FlashLoanError::ProtocolFeeTooHigh);
+
+        let flashloan = &mut ctx.accounts.flashloan;
+        flashloan.protocol_fee = protocol_fee;
+        flashloan.treasury = treasury;
+
+        Ok(())
+    }
+
+    /// First step of an authority transfer, `new_authority` takes over once it accepts
+    pub fn propose_authority(ctx: Context<ProposeAuthority>, new_authority: Pubkey) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(None, &ctx.accounts.authority, ctx.remaining_accounts)?;
+
+        ctx.accounts.flashloan.pending_authority = Some(new_authority);
+
+        Ok(())
+    }
+
+    /// Second step of an authority transfer, signed by the proposed authority
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let flashloan = &mut ctx.accounts.flashloan;
+        let new_authority = ctx.accounts.new_authority.key();
+        require!(flashloan.pending_authority == Some(new_authority), FlashLoanError::NotPendingAuthority);
+
+        emit!(AuthorityTransferredEvent {
+            flashloan: flashloan.key(),
+            old_authority: flashloan.authority,
+            new_authority,
+        });
+
+        flashloan.authority = new_authority;
+        flashloan.pending_authority = None;
+
+        Ok(())
+    }
+
+    /// Assign `role` to `member`, the authority holds every role in addition to its member
+    pub fn set_role(ctx: Context<SetRole>, role: Role, member: Pubkey) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(None, &ctx.accounts.authority, ctx.remaining_accounts)?;
+
+        let key = ctx.accounts.flashloan.key();
+        let flashloan = &mut ctx.accounts.flashloan;
+        let holder = match role {
+            Role::PoolAdmin => &mut flashloan.pool_admin,
+            Role::FeeManager => &mut flashloan.fee_manager,
+            Role::Pauser => &mut flashloan.pauser,
+            Role::VoucherIssuer => &mut flashloan.voucher_issuer,
+        };
+
+        emit!(RoleUpdatedEvent {
+            flashloan: key,
+            role,
+            old_member: *holder,
+            new_member: member,
+        });
+
+        *holder = member;
+
+        Ok(())
+    }
+
+    /// Stop or resume deposits and new loans, repays and withdrawals stay open
+    pub fn set_paused(ctx: Context<SetPaused>, paused: bool) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(Some(Role::Pauser), &ctx.accounts.pauser, ctx.remaining_accounts)?;
+
+        ctx.accounts.flashloan.paused = paused;
+
+        emit!(PausedEvent {
+            flashloan: ctx.accounts.flashloan.key(),
+            paused,
+        });
+
+        Ok(())
+    }
+
+    /// Require `multisig.threshold` of its keys for duties of the authority, `None` hands them back to the
+    /// authority key
+    pub fn set_multisig(ctx: Context<SetMultisig>, multisig: Option<Multisig>) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(None, &ctx.accounts.authority, ctx.remaining_accounts)?;
+
+        if let Some(multisig) = &multisig {
+            multisig.validate()?;
+        }
+
+        let flashloan = &mut ctx.accounts.flashloan;
+
+        emit!(MultisigUpdatedEvent {
+            flashloan: flashloan.key(),
+            old_multisig: flashloan.multisig.clone(),
+            new_multisig: multisig.clone(),
+        });
+
+        flashloan.multisig = multisig;
 
         Ok(())
     }
 
-    /// Add pool for a given token mint, setup a pool, token account and lp token mint
-    pub fn add_pool(ctx: Context<AddPool>, fee: u32, discounted_fee: u32) -> Result<()> {
+    /// Change the fee cap for pools, applies to fees set from now on
+    pub fn set_max_fee(ctx: Context<SetMaxFee>, max_fee: u64) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(None, &ctx.accounts.authority, ctx.remaining_accounts)?;
+
+        let max_fee = Fee::from_rate(max_fee);
+        require!(max_fee <= Fee::MAX, FlashLoanError::MaxFeeTooHigh);
+
+        ctx.accounts.flashloan.max_fee = max_fee;
+
+        Ok(())
+    }
+
+    /// Converts a FlashLoan stored in an older layout, fees in basis points become rates
+    pub fn migrate_flashloan(ctx: Context<MigrateFlashLoan>) -> Result<()> {
+        let flashloan = ctx.accounts.flashloan.to_account_info();
+        let migrated = load_outdated_flashloan(&flashloan, ctx.program_id)?;
+
+        store(
+            &flashloan,
+            &migrated,
+            FlashLoan::LEN,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+        )
+    }
+
+    /// Converts a Pool stored in an older layout, fees in basis points become rates
+    pub fn migrate_pool(ctx: Context<MigratePool>) -> Result<()> {
+        let pool = ctx.accounts.pool.to_account_info();
+        let migrated = load_outdated_pool(&pool, &ctx.accounts.flashloan.key(), ctx.program_id)?;
+
+        store(
+            &pool,
+            &migrated,
+            Pool::LEN,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+        )
+    }
+
+    /// Add pool for a given token mint, setup a pool, token account and lp token mint.
+    /// Fees are rates scaled by `Fee::SCALE`
+    pub fn add_pool(ctx: Context<AddPool>, fee: u64, discounted_fee: u64) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(Some(Role::PoolAdmin), &ctx.accounts.pool_admin, ctx.remaining_accounts)?;
+
+        let fee = Fee::from_rate(fee);
+        let discounted_fee = Fee::from_rate(discounted_fee);
+        ctx.accounts.flashloan.validate_pool_fees(&fee, &discounted_fee)?;
+
         let pool = &mut ctx.accounts.pool;
 
+        pool.version = Pool::VERSION;
         pool.bump = *ctx.bumps.get("pool").unwrap();
         pool.borrowing = false;
         pool.token_mint = ctx.accounts.token_mint.key();
         pool.pool_token = ctx.accounts.pool_token.key();
         pool.lp_token_mint = ctx.accounts.lp_token_mint.key();
-        pool.fee = Fee::from_basis_points(fee);
-        pool.discounted_fee = Fee::from_basis_points(discounted_fee);
+        pool.fee = fee;
+        pool.discounted_fee = discounted_fee;
+
+        Ok(())
+    }
+
+    /// Change the pool fees, discounted_fee <= fee <= max_fee.
+    /// `min_fee` is the lowest absolute fee charged for any loan, zero disables it,
+    /// up to MAX_MIN_FEE and never above what max_fee charges for the loan
+    pub fn update_pool(ctx: Context<UpdatePool>, fee: u64, discounted_fee: u64, min_fee: u64) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(Some(Role::FeeManager), &ctx.accounts.fee_manager, ctx.remaining_accounts)?;
+
+        let fee = Fee::from_rate(fee);
+        let discounted_fee = Fee::from_rate(discounted_fee);
+        ctx.accounts.flashloan.validate_pool_fees(&fee, &discounted_fee)?;
+        require!(min_fee <= MAX_MIN_FEE, FlashLoanError::MinFeeTooHigh);
+
+        let pool = &mut ctx.accounts.pool;
+
+        emit!(PoolUpdatedEvent {
+            pool: pool.key(),
+            old_fee: pool.fee,
+            new_fee: fee,
+            old_discounted_fee: pool.discounted_fee,
+            new_discounted_fee: discounted_fee,
+            old_min_fee: pool.min_fee,
+            new_min_fee: min_fee,
+        });
+
+        pool.fee = fee;
+        pool.discounted_fee = discounted_fee;
+        pool.min_fee = min_fee;
+
+        Ok(())
+    }
+
+    /// Make the pool fee depend on the share of the vault a loan takes, `None` restores the flat fee
+    pub fn set_fee_curve(ctx: Context<UpdatePool>, fee_curve: Option<FeeCurve>) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(Some(Role::FeeManager), &ctx.accounts.fee_manager, ctx.remaining_accounts)?;
+
+        if let Some(curve) = &fee_curve {
+            ctx.accounts.flashloan.validate_fee_curve(curve)?;
+        }
+
+        let pool = &mut ctx.accounts.pool;
+
+        emit!(FeeCurveUpdatedEvent {
+            pool: pool.key(),
+            old_fee_curve: pool.fee_curve,
+            new_fee_curve: fee_curve,
+        });
+
+        pool.fee_curve = fee_curve;
+
+        Ok(())
+    }
+
+    /// Charge loans of at least a tier `min_amount` that tier fee instead of the flat fee or curve,
+    /// tiers are sorted by ascending `min_amount`, an empty table disables them
+    pub fn set_fee_tiers(ctx: Context<UpdatePool>, fee_tiers: Vec<FeeTier>) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(Some(Role::FeeManager), &ctx.accounts.fee_manager, ctx.remaining_accounts)?;
+
+        ctx.accounts.flashloan.validate_fee_tiers(&fee_tiers)?;
+
+        let pool = &mut ctx.accounts.pool;
+
+        emit!(FeeTiersUpdatedEvent {
+            pool: pool.key(),
+            old_fee_tiers: pool.fee_tiers.clone(),
+            new_fee_tiers: fee_tiers.clone(),
+        });
+
+        pool.fee_tiers = fee_tiers;
+
+        Ok(())
+    }
+
+    /// Replace the Merkle root of the `(borrower, fee)` partner allowlist, `None` disables it, see `merkle`
+    pub fn set_allowlist_root(ctx: Context<UpdatePool>, allowlist_root: Option<[u8; 32]>) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(Some(Role::FeeManager), &ctx.accounts.fee_manager, ctx.remaining_accounts)?;
+
+        let pool = &mut ctx.accounts.pool;
+
+        emit!(AllowlistUpdatedEvent {
+            pool: pool.key(),
+            old_allowlist_root: pool.allowlist_root,
+            new_allowlist_root: allowlist_root,
+        });
+
+        pool.allowlist_root = allowlist_root;
+
+        Ok(())
+    }
+
+    /// Grant borrowers holding the governance mint the fee of the highest balance tier they reach,
+    /// `None` disables it
+    pub fn set_governance_discount(
+        ctx: Context<UpdatePool>,
+        governance_discount: Option<GovernanceDiscount>,
+    ) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(Some(Role::FeeManager), &ctx.accounts.fee_manager, ctx.remaining_accounts)?;
+
+        if let Some(discount) = &governance_discount {
+            ctx.accounts.flashloan.validate_fee_tiers(&discount.tiers)?;
+        }
+
+        let pool = &mut ctx.accounts.pool;
+
+        emit!(GovernanceDiscountUpdatedEvent {
+            pool: pool.key(),
+            old_governance_discount: pool.governance_discount.clone(),
+            new_governance_discount: governance_discount.clone(),
+        });
+
+        pool.governance_discount = governance_discount;
 
         Ok(())
     }
 
-    /// Receive tokens and mint lp tokens
-    pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
+    /// Receive tokens from the depositor and mint at least `min_lp_out` lp tokens to the depositor
+    pub fn deposit(ctx: Context<Deposit>, amount: u64, min_lp_out: u64) -> Result<()> {
+        require!(!ctx.accounts.flashloan.paused, FlashLoanError::Paused);
         require!(!ctx.accounts.pool.borrowing, FlashLoanError::Borrowing);
 
         // we need to compute how many tokens return for LP-shares
         let lp_supply = ctx.accounts.lp_token_mint.supply;
-        let token_supply = ctx.accounts.pool_token.amount;
+        let token_supply = lp_value(ctx.accounts.pool_token.amount, ctx.accounts.pool.protocol_fees)?;
         let shares_for_user = shares_from_value(
             amount,
             token_supply,
             lp_supply,
         )?;
+        require!(shares_for_user > 0, FlashLoanError::ZeroShares);
+        require!(shares_for_user >= min_lp_out, FlashLoanError::SlippageExceeded);
 
         let key = ctx.accounts.flashloan.key();
         let seeds = &[
@@ -58,14 +340,13 @@ pub mod flashloan {
         ];
         let singer_seeds = &[&seeds[..]];
 
-        let transfer_ctx = CpiContext::new_with_signer(
+        let transfer_ctx = CpiContext::new(
             ctx.accounts.token_program.to_account_info(),
             Transfer {
                 from: ctx.accounts.user_token.to_account_info(),
                 to: ctx.accounts.pool_token.to_account_info(),
-                authority: ctx.accounts.token_authority.to_account_info(),
+                authority: ctx.accounts.depositor.to_account_info(),
             },
-            singer_seeds,
         );
 
         token::transfer(transfer_ctx, amount)?;
@@ -83,6 +364,7 @@ pub mod flashloan {
         token::mint_to(mint_ctx, shares_for_user)?;
 
         emit!(DepositEvent {
+            depositor: ctx.accounts.depositor.key(),
             token_mint: ctx.accounts.pool.token_mint,
             token_amount: amount,
             lp_amount: shares_for_user,
@@ -91,17 +373,18 @@ pub mod flashloan {
         Ok(())
     }
 
-    /// Burn lp and pay out tokens
-    pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
+    /// Burn lp and pay out at least `min_tokens_out` tokens
+    pub fn withdraw(ctx: Context<Withdraw>, amount: u64, min_tokens_out: u64) -> Result<()> {
         require!(!ctx.accounts.pool.borrowing, FlashLoanError::Borrowing);
 
         let lp_supply = ctx.accounts.lp_token_mint.supply;
-        let token_supply = ctx.accounts.pool_token.amount;
+        let token_supply = lp_value(ctx.accounts.pool_token.amount, ctx.accounts.pool.protocol_fees)?;
         let tokens_for_user = value_from_shares(
             amount,
             token_supply,
             lp_supply,
         )?;
+        require!(tokens_for_user >= min_tokens_out, FlashLoanError::SlippageExceeded);
 
         let key = ctx.accounts.flashloan.key();
         let seeds = &[
@@ -110,14 +393,13 @@ pub mod flashloan {
         ];
         let singer_seeds = &[&seeds[..]];
 
-        let burn_ctx = CpiContext::new_with_signer(
+        let burn_ctx = CpiContext::new(
             ctx.accounts.token_program.to_account_info(),
             Burn {
                 mint: ctx.accounts.lp_token_mint.to_account_info(),
                 to: ctx.accounts.user_lp_token.to_account_info(),
-                authority: ctx.accounts.token_authority.to_account_info(),
+                authority: ctx.accounts.withdrawer.to_account_info(),
             },
-            singer_seeds,
         );
 
         token::burn(burn_ctx, amount)?;
@@ -143,57 +425,214 @@ pub mod flashloan {
         Ok(())
     }
 
-    pub fn mint_voucher(ctx: Context<MintVoucher>) -> Result<()> {
+    /// Transfer the protocol share of the fees accrued by the pool to the treasury
+    pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        require!(!ctx.accounts.pool.borrowing, FlashLoanError::Borrowing);
+
+        let amount = ctx.accounts.pool.protocol_fees;
+
+        let key = ctx.accounts.flashloan.key();
+        let seeds = &[
+            key.as_ref(), FLASHLOAN_NAMESPACE.as_ref(),
+            &[ctx.accounts.flashloan.token_authority_bump],
+        ];
+        let singer_seeds = &[&seeds[..]];
+
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.pool_token.to_account_info(),
+                to: ctx.accounts.treasury_token.to_account_info(),
+                authority: ctx.accounts.token_authority.to_account_info(),
+            },
+            singer_seeds,
+        );
+
+        token::transfer(transfer_ctx, amount)?;
+        ctx.accounts.pool.protocol_fees = 0;
+
+        emit!(ProtocolFeesCollectedEvent {
+            token_mint: ctx.accounts.pool.token_mint,
+            treasury_token: ctx.accounts.treasury_token.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    /// Allows the `caller` program to borrow through cpi. The borrow still needs a later top-level
+    /// `repay` or instruction of the caller able to repay it, see `find_caller_repay`
+    pub fn add_trusted_caller(ctx: Context<UpdateTrustedCallers>, caller: Pubkey) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(None, &ctx.accounts.authority, ctx.remaining_accounts)?;
+
+        let flashloan = &mut ctx.accounts.flashloan;
+
+        require!(!flashloan.is_trusted_caller(&caller), FlashLoanError::AlreadyTrustedCaller);
+        require!(flashloan.trusted_callers.len() < MAX_TRUSTED_CALLERS, FlashLoanError::TooManyTrustedCallers);
+        flashloan.trusted_callers.push(caller);
+
+        Ok(())
+    }
+
+    pub fn remove_trusted_caller(ctx: Context<UpdateTrustedCallers>, caller: Pubkey) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(None, &ctx.accounts.authority, ctx.remaining_accounts)?;
+
+        let flashloan = &mut ctx.accounts.flashloan;
+
+        require!(flashloan.is_trusted_caller(&caller), FlashLoanError::UnknownTrustedCaller);
+        flashloan.trusted_callers.retain(|trusted| *trusted != caller);
+
+        Ok(())
+    }
+
+    /// Issue the `beneficiary` voucher for the pool, their borrows cost `fee` or, when `None`, the pool
+    /// discounted_fee. The voucher is valid until `expires_at`, for `max_uses` borrows totalling
+    /// `max_total_volume`, when set
+    pub fn mint_voucher(
+        ctx: Context<MintVoucher>,
+        beneficiary: Pubkey,
+        fee: Option<u64>,
+        expires_at: Option<i64>,
+        max_uses: Option<u64>,
+        max_total_volume: Option<u64>,
+    ) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(Some(Role::VoucherIssuer), &ctx.accounts.voucher_issuer, ctx.remaining_accounts)?;
+
+        let voucher = &mut ctx.accounts.voucher;
+        voucher.bump = *ctx.bumps.get("voucher").unwrap();
+        voucher.pool = ctx.accounts.pool.key();
+        voucher.beneficiary = beneficiary;
+        voucher.kind = VoucherKind::Beneficiary;
+        voucher.set_terms(&ctx.accounts.pool, fee, expires_at, max_uses, max_total_volume)
+    }
+
+    /// Issue a transferable voucher, a supply-1 token minted to `recipient_token`. Whoever holds it
+    /// gets the voucher terms, see mint_voucher
+    pub fn mint_nft_voucher(
+        ctx: Context<MintNftVoucher>,
+        fee: Option<u64>,
+        expires_at: Option<i64>,
+        max_uses: Option<u64>,
+        max_total_volume: Option<u64>,
+    ) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(Some(Role::VoucherIssuer), &ctx.accounts.voucher_issuer, ctx.remaining_accounts)?;
+
         let voucher = &mut ctx.accounts.voucher;
+        voucher.bump = *ctx.bumps.get("voucher").unwrap();
         voucher.pool = ctx.accounts.pool.key();
+        voucher.beneficiary = ctx.accounts.voucher_mint.key();
+        voucher.kind = VoucherKind::Nft;
+        voucher.set_terms(&ctx.accounts.pool, fee, expires_at, max_uses, max_total_volume)?;
+
+        let key = ctx.accounts.flashloan.key();
+        let seeds = &[
+            key.as_ref(), FLASHLOAN_NAMESPACE.as_ref(),
+            &[ctx.accounts.flashloan.token_authority_bump],
+        ];
+        let singer_seeds = &[&seeds[..]];
+
+        let mint_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            MintTo {
+                mint: ctx.accounts.voucher_mint.to_account_info(),
+                to: ctx.accounts.recipient_token.to_account_info(),
+                authority: ctx.accounts.token_authority.to_account_info(),
+            },
+            singer_seeds,
+        );
+
+        token::mint_to(mint_ctx, 1)?;
+
+        // no more tokens can be minted, the supply stays at one
+        let authority_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            SetAuthority {
+                account_or_mint: ctx.accounts.voucher_mint.to_account_info(),
+                current_authority: ctx.accounts.token_authority.to_account_info(),
+            },
+            singer_seeds,
+        );
+
+        token::set_authority(authority_ctx, AuthorityType::MintTokens, None)
+    }
+
+    /// Close the voucher, its rent goes back to the voucher issuer
+    pub fn revoke_voucher(ctx: Context<RevokeVoucher>) -> Result<()> {
+        ctx.accounts.flashloan.require_cosigners(Some(Role::VoucherIssuer), &ctx.accounts.voucher_issuer, ctx.remaining_accounts)?;
 
         Ok(())
     }
 
-    // Confirms there exists a matching repay, then lends tokens
-    pub fn borrow(ctx: Context<Borrow>, amount: u64) -> Result<()> {
+    // Confirms there exists a matching repay, then lends tokens.
+    // `allowlist_proof` claims the borrower partner rate from the pool allowlist
+    pub fn borrow<'info>(
+        ctx: Context<'_, '_, '_, 'info, Borrow<'info>>,
+        amount: u64,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.flashloan.paused, FlashLoanError::Paused);
         require!(!ctx.accounts.pool.borrowing, FlashLoanError::Borrowing);
 
         let ixns = ctx.accounts.instructions.to_account_info();
 
-        // make sure this isn't a cpi call
-        let current_idx = instructions::load_current_index_checked(&ixns)? as usize;
-        let current_ixn = instructions::load_instruction_at_checked(current_idx, &ixns)?;
-        require!(current_ixn.program_id == *ctx.program_id, FlashLoanError::CpiBorrow);
+        // look through the following instructions for an equivalent repay to this borrow,
+        // the repaid amount itself is verified by balance in repay
+        let (current_idx, current_ixn) = load_current_instruction(&ixns)?;
+        if current_ixn.program_id == *ctx.program_id {
+            find_repay(
+                &ixns,
+                current_idx,
+                ctx.program_id,
+                &ctx.accounts.flashloan.key(),
+                &ctx.accounts.pool.key(),
+                &ctx.accounts.pool_token.key(),
+            )?;
+        } else {
+            // cpi borrow, only trusted callers repaying in a later instruction
+            require!(
+                ctx.accounts.flashloan.is_trusted_caller(&current_ixn.program_id),
+                FlashLoanError::CpiBorrow
+            );
+            find_caller_repay(
+                &ixns,
+                current_idx,
+                ctx.program_id,
+                &current_ixn.program_id,
+                &ctx.accounts.flashloan.key(),
+                &ctx.accounts.pool.key(),
+                &ctx.accounts.pool_token.key(),
+            )?;
+        }
 
-        // loop through instructions, looking for an equivalent repay to this borrow
-        let mut idx = current_idx + 1;
-        let expected_sighash = u64::from_be_bytes(Repay::SIGHASH[..8].try_into().unwrap());
-
-        let fee = if !Voucher::is_discounted_borrow(&ctx) { &ctx.accounts.pool.fee } else { &ctx.accounts.pool.discounted_fee };
-
-        let expected_repay =
-            amount
-            .checked_add(fee.apply(amount))
-                .ok_or_else(|| error!(FlashLoanError::CalculationFailure))?;
-
-        loop {
-            // get the next instruction, die if theres no more
-            if let Ok(ixn) = instructions::load_instruction_at_checked(idx, &ixns) {
-                let actual_sighash = u64::from_be_bytes(ixn.data[..8].try_into().unwrap());
-
-                // check if we have a toplevel repay toward the same pool
-                // if so, confirm the amount, otherwise next instruction
-                if ixn.program_id == *ctx.program_id
-                    && actual_sighash == expected_sighash
-                    && ixn.accounts[2].pubkey == ctx.accounts.pool.key() {
-                    if u64::from_le_bytes(ixn.data[8..16].try_into().unwrap()) == expected_repay {
-                        break;
-                    } else {
-                        return Err(error!(FlashLoanError::IncorrectRepay));
-                    }
-                } else {
-                    idx += 1;
+        let allowlist_fee = match &allowlist_proof {
+            Some(proof) => Some(ctx.accounts.pool.allowlist_fee(&ctx.accounts.borrower.key(), proof)?),
+            None => None,
+        };
+        let governance_fee = ctx.accounts.pool.governance_fee(&ctx.accounts.borrower.key(), ctx.remaining_accounts);
+        let mut discount = allowlist_fee.into_iter().chain(governance_fee).min();
+
+        // a voucher is only redeemed when it lowers the fee the other discounts leave
+        if let Some(mut voucher) = Voucher::held_voucher(&ctx)? {
+            let voucher_fee = voucher.fee.unwrap_or(ctx.accounts.pool.discounted_fee);
+            if ctx.accounts.pool.lowers_fee(
+                amount,
+                ctx.accounts.pool_token.amount,
+                discount,
+                voucher_fee,
+                &ctx.accounts.flashloan.max_fee,
+            ) {
+                voucher.redeem(amount, Clock::get()?.unix_timestamp)?;
+                if voucher.is_limited() {
+                    require!(voucher.to_account_info().is_writable, ErrorCode::ConstraintMut);
+                    voucher.exit(ctx.program_id)?;
                 }
-            } else {
-                return Err(error!(FlashLoanError::NoRepay));
+                discount = Some(voucher_fee);
             }
         }
+        let fee = ctx.accounts.pool.borrow_fee(amount, ctx.accounts.pool_token.amount, discount);
+
+        let fee_amount = ctx.accounts.pool.loan_fee_for(&fee, amount, &ctx.accounts.flashloan.max_fee);
+        let tier = ctx.accounts.pool.fee_tier(amount);
 
         let key = ctx.accounts.flashloan.key();
         let seeds = &[
@@ -212,25 +651,189 @@ pub mod flashloan {
             singer_seeds,
         );
 
+        let pre_borrow_balance = ctx.accounts.pool_token.amount;
         token::transfer(transfer_ctx, amount)?;
-        ctx.accounts.pool.borrowing = true;
+
+        ctx.accounts.pool.open_loan(amount, fee_amount, pre_borrow_balance);
 
         emit!(BorrowEvent{
+            borrower: ctx.accounts.borrower.key(),
             token_mint: ctx.accounts.pool.token_mint,
             amount,
+            tier,
         });
 
         Ok(())
     }
 
+    /// Pays back the outstanding loan, the pool balance must cover the loan plus fee
     pub fn repay(ctx: Context<Repay>, amount: u64) -> Result<()> {
+        require!(ctx.accounts.pool.borrowing, FlashLoanError::NotBorrowing);
+
+        let ixns = ctx.accounts.instructions.to_account_info();
+
+        // make sure this isn't a cpi call from an untrusted program
+        let (_, current_ixn) = load_current_instruction(&ixns)?;
+        require!(
+            current_ixn.program_id == *ctx.program_id
+                || ctx.accounts.flashloan.is_trusted_caller(&current_ixn.program_id),
+            FlashLoanError::CpiRepay
+        );
+
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.user_token.to_account_info(),
+                to: ctx.accounts.pool_token.to_account_info(),
+                authority: ctx.accounts.borrower.to_account_info(),
+            },
+        );
+
+        token::transfer(transfer_ctx, amount)?;
+
+        ctx.accounts.pool_token.reload()?;
+        ctx.accounts.pool.close_loan(ctx.accounts.pool_token.amount, &ctx.accounts.flashloan.protocol_fee)?;
+
+        emit!(RepayEvent{
+            borrower: ctx.accounts.borrower.key(),
+            token_mint: ctx.accounts.pool.token_mint,
+            amount,
+        });
+
+        Ok(())
+
+    }
+
+    /// Borrows from several pools at once, one `amounts` entry per (pool, pool_token, user_token)
+    /// group in the remaining accounts. Confirms there exists a matching repay_many, then lends tokens
+    pub fn borrow_many<'info>(
+        ctx: Context<'_, '_, '_, 'info, BorrowMany<'info>>,
+        amounts: Vec<u64>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.flashloan.paused, FlashLoanError::Paused);
+
+        let legs = BatchLeg::load_all(
+            ctx.remaining_accounts,
+            amounts.len(),
+            &ctx.accounts.flashloan.key(),
+            &ctx.accounts.borrower.key(),
+            ctx.program_id,
+        )?;
+
         let ixns = ctx.accounts.instructions.to_account_info();
 
         // make sure this isn't a cpi call
-        let current_idx = instructions::load_current_index_checked(&ixns)? as usize;
-        let current_ixn = instructions::load_instruction_at_checked(current_idx, &ixns)?;
+        let (current_idx, current_ixn) = load_current_instruction(&ixns)?;
         require!(current_ixn.program_id == *ctx.program_id, FlashLoanError::CpiBorrow);
 
+        let pools: Vec<(Pubkey, Pubkey)> = legs.iter()
+            .map(|leg| (leg.pool.key(), leg.pool_token.key()))
+            .collect();
+        find_repay_many(&ixns, current_idx, ctx.program_id, &ctx.accounts.flashloan.key(), &pools)?;
+
+        let key = ctx.accounts.flashloan.key();
+        let seeds = &[
+            key.as_ref(), FLASHLOAN_NAMESPACE.as_ref(),
+            &[ctx.accounts.flashloan.token_authority_bump],
+        ];
+        let singer_seeds = &[&seeds[..]];
+
+        for (mut leg, amount) in legs.into_iter().zip(amounts) {
+            // a pool listed twice is already borrowing once the first leg is written back
+            leg.pool.reload()?;
+            require!(!leg.pool.borrowing, FlashLoanError::Borrowing);
+
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: leg.pool_token.to_account_info(),
+                    to: leg.user_token.to_account_info(),
+                    authority: ctx.accounts.token_authority.to_account_info(),
+                },
+                singer_seeds,
+            );
+
+            let pre_borrow_balance = leg.pool_token.amount;
+            token::transfer(transfer_ctx, amount)?;
+
+            let fee = leg.pool.borrow_fee(amount, pre_borrow_balance, None);
+            let fee_amount = leg.pool.loan_fee_for(&fee, amount, &ctx.accounts.flashloan.max_fee);
+            leg.pool.open_loan(amount, fee_amount, pre_borrow_balance);
+            leg.pool.exit(ctx.program_id)?;
+
+            emit!(BorrowEvent{
+                borrower: ctx.accounts.borrower.key(),
+                token_mint: leg.pool.token_mint,
+                amount,
+                tier: leg.pool.fee_tier(amount),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Pays back every loan opened by borrow_many, each pool balance must cover its loan plus fee
+    pub fn repay_many<'info>(
+        ctx: Context<'_, '_, '_, 'info, RepayMany<'info>>,
+        amounts: Vec<u64>,
+    ) -> Result<()> {
+        let legs = BatchLeg::load_all(
+            ctx.remaining_accounts,
+            amounts.len(),
+            &ctx.accounts.flashloan.key(),
+            &ctx.accounts.borrower.key(),
+            ctx.program_id,
+        )?;
+
+        let ixns = ctx.accounts.instructions.to_account_info();
+
+        // make sure this isn't a cpi call
+        let (_, current_ixn) = load_current_instruction(&ixns)?;
+        require!(current_ixn.program_id == *ctx.program_id, FlashLoanError::CpiRepay);
+
+        for (mut leg, amount) in legs.into_iter().zip(amounts) {
+            leg.pool.reload()?;
+            require!(leg.pool.borrowing, FlashLoanError::NotBorrowing);
+
+            let transfer_ctx = CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: leg.user_token.to_account_info(),
+                    to: leg.pool_token.to_account_info(),
+                    authority: ctx.accounts.borrower.to_account_info(),
+                },
+            );
+
+            token::transfer(transfer_ctx, amount)?;
+
+            leg.pool_token.reload()?;
+            leg.pool.close_loan(leg.pool_token.amount, &ctx.accounts.flashloan.protocol_fee)?;
+            leg.pool.exit(ctx.program_id)?;
+
+            emit!(RepayEvent{
+                borrower: ctx.accounts.borrower.key(),
+                token_mint: leg.pool.token_mint,
+                amount,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Callback flash loan, lends tokens to the receiver token account and invokes
+    /// `on_flash_loan` of the receiver program with the remaining accounts, see `receiver`.
+    /// The pool balance must cover the loan plus fee once the receiver returns
+    pub fn flash_loan<'info>(
+        ctx: Context<'_, '_, '_, 'info, FlashLoanWithCallback<'info>>,
+        amount: u64,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.flashloan.paused, FlashLoanError::Paused);
+        require!(!ctx.accounts.pool.borrowing, FlashLoanError::Borrowing);
+
+        let fee = ctx.accounts.pool.borrow_fee(amount, ctx.accounts.pool_token.amount, None);
+        let fee_amount = ctx.accounts.pool.loan_fee_for(&fee, amount, &ctx.accounts.flashloan.max_fee);
+
         let key = ctx.accounts.flashloan.key();
         let seeds = &[
             key.as_ref(), FLASHLOAN_NAMESPACE.as_ref(),
@@ -241,23 +844,45 @@ pub mod flashloan {
         let transfer_ctx = CpiContext::new_with_signer(
             ctx.accounts.token_program.to_account_info(),
             Transfer {
-                from: ctx.accounts.user_token.to_account_info(),
-                to: ctx.accounts.pool_token.to_account_info(),
+                from: ctx.accounts.pool_token.to_account_info(),
+                to: ctx.accounts.receiver_token.to_account_info(),
                 authority: ctx.accounts.token_authority.to_account_info(),
             },
             singer_seeds,
         );
 
+        let pre_borrow_balance = ctx.accounts.pool_token.amount;
         token::transfer(transfer_ctx, amount)?;
-        ctx.accounts.pool.borrowing = false;
+        ctx.accounts.pool.open_loan(amount, fee_amount, pre_borrow_balance);
+
+        // the receiver is invoked without the token authority signature
+        let callback = on_flash_loan_instruction(
+            ctx.accounts.receiver_program.key(),
+            ctx.remaining_accounts,
+            &OnFlashLoanArgs {
+                initiator: ctx.accounts.initiator.key(),
+                token_mint: ctx.accounts.pool.token_mint,
+                amount,
+                fee: fee_amount,
+                data,
+            },
+        )?;
+        let mut callback_accounts = ctx.remaining_accounts.to_vec();
+        callback_accounts.push(ctx.accounts.receiver_program.to_account_info());
+        invoke(&callback, &callback_accounts)?;
 
-        emit!(RepayEvent{
+        ctx.accounts.pool_token.reload()?;
+        ctx.accounts.pool.close_loan(ctx.accounts.pool_token.amount, &ctx.accounts.flashloan.protocol_fee)?;
+
+        emit!(FlashLoanEvent{
+            initiator: ctx.accounts.initiator.key(),
+            receiver_program: ctx.accounts.receiver_program.key(),
             token_mint: ctx.accounts.pool.token_mint,
             amount,
+            fee: fee_amount,
         });
 
         Ok(())
-
     }
 }
 
@@ -266,10 +891,21 @@ pub mod flashloan {
 pub const FLASHLOAN_NAMESPACE: [u8; 9] = *b"flashloan";
 pub const TOKEN_NAMESPACE: [u8; 5] = *b"token";
 pub const LP_TOKEN_NAMESPACE: [u8; 14] = *b"liquidity_pool";
+pub const VOUCHER_NAMESPACE: [u8; 7] = *b"voucher";
 
-// ----------------------------------------------------------------------------
+pub const MAX_TRUSTED_CALLERS: usize = 8;
+pub const MAX_FEE_TIERS: usize = 4;
+pub const MAX_MULTISIG_SIGNERS: usize = 5;
 
-#[derive(Accounts)]
+/// Highest pool min_fee, in base units of the pool token
+pub const MAX_MIN_FEE: u64 = 1_000_000;
+
+/// Remaining accounts per pool in borrow_many and repay_many: pool, pool_token, user_token
+pub const BATCH_LEG_ACCOUNTS: usize = 3;
+
+// ----------------------------------------------------------------------------
+
+#[derive(Accounts)]
 pub struct Initialize<'info> {
     #[account(
         init,
@@ -294,9 +930,96 @@ pub struct Initialize<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct ProposeAuthority<'info> {
+    #[account(mut)]
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(constraint = flashloan.may_sign(None, &authority.key()) @ FlashLoanError::MissingRole)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    #[account(mut)]
+    pub flashloan: Account<'info, FlashLoan>,
+
+    pub new_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetRole<'info> {
+    #[account(mut)]
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(constraint = flashloan.may_sign(None, &authority.key()) @ FlashLoanError::MissingRole)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetMultisig<'info> {
+    #[account(mut)]
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(constraint = flashloan.may_sign(None, &authority.key()) @ FlashLoanError::MissingRole)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaused<'info> {
+    #[account(mut)]
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(constraint = flashloan.may_sign(Some(Role::Pauser), &pauser.key()) @ FlashLoanError::MissingRole)]
+    pub pauser: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetMaxFee<'info> {
+    #[account(mut)]
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(constraint = flashloan.may_sign(None, &authority.key()) @ FlashLoanError::MissingRole)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetProtocolFee<'info> {
+    #[account(mut)]
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(constraint = flashloan.may_sign(None, &authority.key()) @ FlashLoanError::MissingRole)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateFlashLoan<'info> {
+    #[account(mut, owner = crate::ID)]
+    /// CHECK: Legacy layout, checked in migration
+    pub flashloan: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePool<'info> {
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(mut, owner = crate::ID)]
+    /// CHECK: Legacy layout, checked in migration
+    pub pool: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct AddPool<'info> {
-    #[account(has_one = authority)]
     pub flashloan: Box<Account<'info, FlashLoan>>,
 
     #[account(
@@ -306,7 +1029,8 @@ pub struct AddPool<'info> {
     /// CHECK: Checked above, used only for bump calc
     pub token_authority: UncheckedAccount<'info>,
 
-    pub authority: Signer<'info>,
+    #[account(constraint = flashloan.may_sign(Some(Role::PoolAdmin), &pool_admin.key()) @ FlashLoanError::MissingRole)]
+    pub pool_admin: Signer<'info>,
 
     #[account(mut)]
     pub payer: Signer<'info>,
@@ -347,6 +1071,21 @@ pub struct AddPool<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct UpdatePool<'info> {
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(constraint = flashloan.may_sign(Some(Role::FeeManager), &fee_manager.key()) @ FlashLoanError::MissingRole)]
+    pub fee_manager: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [flashloan.key().as_ref(), pool.token_mint.as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
 #[derive(Accounts)]
 pub struct Deposit<'info> {
     pub flashloan: Account<'info, FlashLoan>,
@@ -378,10 +1117,20 @@ pub struct Deposit<'info> {
     )]
     pub lp_token_mint: Account<'info, Mint>,
 
-    #[account(mut, constraint =  user_token.mint == pool.token_mint)]
+    pub depositor: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = user_token.mint == pool.token_mint,
+        constraint = user_token.owner == depositor.key(),
+    )]
     pub user_token: Account<'info, TokenAccount>,
 
-    #[account(mut, constraint = user_lp_token.mint == pool.lp_token_mint)]
+    #[account(
+        mut,
+        constraint = user_lp_token.mint == pool.lp_token_mint,
+        constraint = user_lp_token.owner == depositor.key(),
+    )]
     pub user_lp_token: Account<'info, TokenAccount>,
 
     pub token_program: Program<'info, Token>,
@@ -418,21 +1167,76 @@ pub struct Withdraw<'info> {
     )]
     pub lp_token_mint: Account<'info, Mint>,
 
-    #[account(mut, constraint =  user_token.mint == pool.token_mint)]
+    pub withdrawer: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = user_token.mint == pool.token_mint,
+        constraint = user_token.owner == withdrawer.key(),
+    )]
     pub user_token: Account<'info, TokenAccount>,
 
-    #[account(mut, constraint = user_lp_token.mint == pool.lp_token_mint)]
+    #[account(
+        mut,
+        constraint = user_lp_token.mint == pool.lp_token_mint,
+        constraint = user_lp_token.owner == withdrawer.key(),
+    )]
     pub user_lp_token: Account<'info, TokenAccount>,
 
     pub token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
-pub struct MintVoucher<'info> {
-    #[account(has_one = authority)]
+pub struct CollectProtocolFees<'info> {
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(
+        seeds = [flashloan.key().as_ref(), FLASHLOAN_NAMESPACE.as_ref()],
+        bump = flashloan.token_authority_bump
+    )]
+    /// CHECK: Checked above, used only for bump calc
+    pub token_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [flashloan.key().as_ref(), pool.token_mint.as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [flashloan.key().as_ref(), TOKEN_NAMESPACE.as_ref(), pool.token_mint.as_ref()],
+        bump
+    )]
+    pub pool_token: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = treasury_token.mint == pool.token_mint,
+        constraint = treasury_token.owner == flashloan.treasury,
+    )]
+    pub treasury_token: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateTrustedCallers<'info> {
+    #[account(mut)]
     pub flashloan: Account<'info, FlashLoan>,
 
+    #[account(constraint = flashloan.may_sign(None, &authority.key()) @ FlashLoanError::MissingRole)]
     pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(beneficiary: Pubkey)]
+pub struct MintVoucher<'info> {
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(constraint = flashloan.may_sign(Some(Role::VoucherIssuer), &voucher_issuer.key()) @ FlashLoanError::MissingRole)]
+    pub voucher_issuer: Signer<'info>,
 
     #[account(
         seeds = [flashloan.key().as_ref(), pool.token_mint.as_ref()],
@@ -440,7 +1244,13 @@ pub struct MintVoucher<'info> {
     )]
     pub pool: Account<'info, Pool>,
 
-    #[account(init, payer = payer, space = Voucher::LEN)]
+    #[account(
+        init,
+        payer = payer,
+        space = Voucher::LEN,
+        seeds = [pool.key().as_ref(), VOUCHER_NAMESPACE.as_ref(), beneficiary.as_ref()],
+        bump,
+    )]
     pub voucher: Account<'info, Voucher>,
 
     #[account(mut)]
@@ -449,6 +1259,87 @@ pub struct MintVoucher<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct MintNftVoucher<'info> {
+    pub flashloan: Box<Account<'info, FlashLoan>>,
+
+    #[account(
+        seeds = [flashloan.key().as_ref(), FLASHLOAN_NAMESPACE.as_ref()],
+        bump = flashloan.token_authority_bump
+    )]
+    /// CHECK: Checked above, used only for bump calc
+    pub token_authority: UncheckedAccount<'info>,
+
+    #[account(constraint = flashloan.may_sign(Some(Role::VoucherIssuer), &voucher_issuer.key()) @ FlashLoanError::MissingRole)]
+    pub voucher_issuer: Signer<'info>,
+
+    #[account(
+        seeds = [flashloan.key().as_ref(), pool.token_mint.as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = Voucher::LEN,
+        seeds = [pool.key().as_ref(), VOUCHER_NAMESPACE.as_ref(), voucher_mint.key().as_ref()],
+        bump,
+    )]
+    pub voucher: Box<Account<'info, Voucher>>,
+
+    #[account(
+        init,
+        payer = payer,
+        mint::authority = token_authority,
+        mint::decimals = 0,
+    )]
+    pub voucher_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = payer,
+        token::mint = voucher_mint,
+        token::authority = recipient,
+    )]
+    pub recipient_token: Account<'info, TokenAccount>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub recipient: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeVoucher<'info> {
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(
+        mut,
+        constraint = flashloan.may_sign(Some(Role::VoucherIssuer), &voucher_issuer.key()) @ FlashLoanError::MissingRole,
+    )]
+    pub voucher_issuer: Signer<'info>,
+
+    #[account(
+        seeds = [flashloan.key().as_ref(), pool.token_mint.as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [pool.key().as_ref(), VOUCHER_NAMESPACE.as_ref(), voucher.beneficiary.as_ref()],
+        bump = voucher.bump,
+        close = voucher_issuer,
+    )]
+    pub voucher: Account<'info, Voucher>,
+}
+
 #[derive(Accounts)]
 pub struct Borrow<'info> {
     pub flashloan: Account<'info, FlashLoan>,
@@ -474,9 +1365,16 @@ pub struct Borrow<'info> {
     )]
     pub pool_token: Account<'info, TokenAccount>,
 
-    #[account(mut, constraint =  user_token.mint == pool.token_mint)]
+    #[account(
+        mut,
+        constraint = user_token.mint == pool.token_mint,
+        constraint = user_token.owner == borrower.key()
+            || user_token.delegate == COption::Some(borrower.key()),
+    )]
     pub user_token: Account<'info, TokenAccount>,
 
+    pub borrower: Signer<'info>,
+
     #[account(address = instructions::ID)]
     /// CHECK: Checked above, sysvar::instructions
     pub instructions: UncheckedAccount<'info>,
@@ -488,13 +1386,6 @@ pub struct Borrow<'info> {
 pub struct Repay<'info> {
     pub flashloan: Account<'info, FlashLoan>,
 
-    #[account(
-        seeds = [flashloan.key().as_ref(), FLASHLOAN_NAMESPACE.as_ref()],
-        bump = flashloan.token_authority_bump
-    )]
-    /// CHECK: Checked above, used only for bump calc
-    pub token_authority: UncheckedAccount<'info>,
-
     #[account(
         mut,
         seeds = [flashloan.key().as_ref(), pool.token_mint.as_ref()],
@@ -509,9 +1400,16 @@ pub struct Repay<'info> {
     )]
     pub pool_token: Account<'info, TokenAccount>,
 
-    #[account(mut, constraint =  user_token.mint == pool.token_mint)]
+    #[account(
+        mut,
+        constraint = user_token.mint == pool.token_mint,
+        constraint = user_token.owner == borrower.key()
+            || user_token.delegate == COption::Some(borrower.key()),
+    )]
     pub user_token: Account<'info, TokenAccount>,
 
+    pub borrower: Signer<'info>,
+
     #[account(address = instructions::ID)]
     /// CHECK: Checked above, sysvar::instructions
     pub instructions: UncheckedAccount<'info>,
@@ -528,81 +1426,761 @@ impl Repay<'_> {
         .finalize();
 }
 
-#[account]
-pub struct FlashLoan {
-    pub token_authority_bump: u8,
-    pub authority: Pubkey,
-}
+#[derive(Accounts)]
+pub struct BorrowMany<'info> {
+    pub flashloan: Account<'info, FlashLoan>,
 
-impl FlashLoan {
-    const LEN: usize = 8 + 1 + 32;
-}
+    #[account(
+        seeds = [flashloan.key().as_ref(), FLASHLOAN_NAMESPACE.as_ref()],
+        bump = flashloan.token_authority_bump
+    )]
+    /// CHECK: Checked above, used only for bump calc
+    pub token_authority: UncheckedAccount<'info>,
 
-#[account]
-pub struct Pool {
-    pub bump: u8,
-    pub borrowing: bool,
-    pub fee: Fee,
-    pub discounted_fee: Fee,
-    pub token_mint: Pubkey,
-    pub pool_token: Pubkey,
-    pub lp_token_mint: Pubkey,
-}
+    pub borrower: Signer<'info>,
 
-impl Pool {
-    const LEN: usize = 8 + 2 + 32*3 + 4*2;
-}
+    #[account(address = instructions::ID)]
+    /// CHECK: Checked above, sysvar::instructions
+    pub instructions: UncheckedAccount<'info>,
 
-#[derive(
-    Clone, Copy, Debug, Default, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, PartialOrd, Ord,
-)]
-pub struct Fee {
-    pub basis_points: u32,
+    pub token_program: Program<'info, Token>,
 }
 
-impl Fee {
-    pub fn from_basis_points(basis_points: u32) -> Self {
-        Self { basis_points }
+#[derive(Accounts)]
+pub struct RepayMany<'info> {
+    pub flashloan: Account<'info, FlashLoan>,
+
+    pub borrower: Signer<'info>,
+
+    #[account(address = instructions::ID)]
+    /// CHECK: Checked above, sysvar::instructions
+    pub instructions: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+impl RepayMany<'_> {
+    const SIGHASH: [u8; 32] = Sha256::new()
+        .update(b"global:repay_many")
+        .finalize();
+}
+
+#[derive(Accounts)]
+pub struct FlashLoanWithCallback<'info> {
+    pub flashloan: Account<'info, FlashLoan>,
+
+    #[account(
+        seeds = [flashloan.key().as_ref(), FLASHLOAN_NAMESPACE.as_ref()],
+        bump = flashloan.token_authority_bump
+    )]
+    /// CHECK: Checked above, used only for bump calc
+    pub token_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [flashloan.key().as_ref(), pool.token_mint.as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [flashloan.key().as_ref(), TOKEN_NAMESPACE.as_ref(), pool.token_mint.as_ref()],
+        bump
+    )]
+    pub pool_token: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = receiver_token.mint == pool.token_mint)]
+    pub receiver_token: Account<'info, TokenAccount>,
+
+    #[account(
+        executable,
+        constraint = receiver_program.key() != crate::ID,
+        constraint = receiver_program.key() != token::ID,
+    )]
+    /// CHECK: Any program implementing the receiver interface
+    pub receiver_program: UncheckedAccount<'info>,
+
+    pub initiator: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+/// Accounts of a single pool passed through the remaining accounts of borrow_many and repay_many
+struct BatchLeg<'info> {
+    pool: Account<'info, Pool>,
+    pool_token: Account<'info, TokenAccount>,
+    user_token: Account<'info, TokenAccount>,
+}
+
+impl<'info> BatchLeg<'info> {
+    /// Loads and validates one leg per `BATCH_LEG_ACCOUNTS` remaining accounts, expecting `count` legs
+    fn load_all(
+        accounts: &[AccountInfo<'info>],
+        count: usize,
+        flashloan: &Pubkey,
+        borrower: &Pubkey,
+        program_id: &Pubkey,
+    ) -> Result<Vec<Self>> {
+        require!(
+            count > 0 && accounts.len() == count * BATCH_LEG_ACCOUNTS,
+            FlashLoanError::InvalidBatch
+        );
+
+        accounts.chunks(BATCH_LEG_ACCOUNTS)
+            .map(|leg| Self::load(leg, flashloan, borrower, program_id))
+            .collect()
+    }
+
+    fn load(
+        accounts: &[AccountInfo<'info>],
+        flashloan: &Pubkey,
+        borrower: &Pubkey,
+        program_id: &Pubkey,
+    ) -> Result<Self> {
+        let pool = Account::<Pool>::try_from(&accounts[0])?;
+        let pool_address = Pubkey::create_program_address(
+            &[flashloan.as_ref(), pool.token_mint.as_ref(), &[pool.bump]],
+            program_id,
+        ).map_err(|_| error!(FlashLoanError::InvalidBatch))?;
+        require!(
+            pool.key() == pool_address && accounts[0].is_writable,
+            FlashLoanError::InvalidBatch
+        );
+
+        let pool_token = Account::<TokenAccount>::try_from(&accounts[1])?;
+        require!(pool_token.key() == pool.pool_token, FlashLoanError::InvalidBatch);
+
+        let user_token = Account::<TokenAccount>::try_from(&accounts[2])?;
+        require!(
+            user_token.mint == pool.token_mint
+                && (user_token.owner == *borrower || user_token.delegate == COption::Some(*borrower)),
+            FlashLoanError::InvalidBatch
+        );
+
+        Ok(Self { pool, pool_token, user_token })
+    }
+}
+
+#[account]
+#[derive(Default)]
+pub struct FlashLoan {
+    /// Layout version, see `migration`
+    pub version: u8,
+    pub token_authority_bump: u8,
+    pub authority: Pubkey,
+    /// Programs allowed to borrow and repay through cpi
+    pub trusted_callers: Vec<Pubkey>,
+    /// Highest fee a pool may charge
+    pub max_fee: Fee,
+    /// Share of every loan fee accrued to the protocol instead of the liquidity providers
+    pub protocol_fee: Fee,
+    /// Owner of the token accounts receiving the protocol fees
+    pub treasury: Pubkey,
+    /// Proposed authority, takes over once it accepts
+    pub pending_authority: Option<Pubkey>,
+    /// Adds pools
+    pub pool_admin: Pubkey,
+    /// Sets pool fees, curves, tiers and discounts
+    pub fee_manager: Pubkey,
+    /// Stops and resumes deposits and new loans
+    pub pauser: Pubkey,
+    /// Mints and revokes vouchers
+    pub voucher_issuer: Pubkey,
+    pub paused: bool,
+    /// Keys standing in for the authority key once set
+    pub multisig: Option<Multisig>,
+}
+
+impl FlashLoan {
+    /// Bumped with every layout change, the previous layout then gets its struct in `migration`
+    pub const VERSION: u8 = 4;
+    const LEN: usize = 8 + 1 + 1 + 32 + 4 + 32*MAX_TRUSTED_CALLERS + Fee::LEN*2 + 32 + 1 + 32 + 32*4 + 1 + 1 + Multisig::LEN;
+
+    /// Key assigned to `role`
+    pub fn role_member(&self, role: Role) -> Pubkey {
+        match role {
+            Role::PoolAdmin => self.pool_admin,
+            Role::FeeManager => self.fee_manager,
+            Role::Pauser => self.pauser,
+            Role::VoucherIssuer => self.voucher_issuer,
+        }
+    }
+
+    /// Whether `signers` may act as `role`, `None` being the duties of the authority. The authority
+    /// holds every role, with a multisig configured its threshold of keys stands in for the authority key
+    pub fn is_admin(&self, role: Option<Role>, signers: &[Pubkey]) -> bool {
+        let member = role.map(|role| self.role_member(role));
+        if let Some(member) = member {
+            if signers.contains(&member) {
+                return true;
+            }
+        }
+
+        match &self.multisig {
+            Some(multisig) => multisig.approves(signers),
+            None => signers.contains(&self.authority),
+        }
+    }
+
+    /// Whether `key` may sign as `role`: the role member, the authority, or with a multisig configured
+    /// one of its keys, whose threshold `require_cosigners` then checks
+    pub fn may_sign(&self, role: Option<Role>, key: &Pubkey) -> bool {
+        let member = role.map(|role| self.role_member(role));
+        if member == Some(*key) {
+            return true;
+        }
+
+        match &self.multisig {
+            Some(multisig) => multisig.signers.contains(key),
+            None => self.authority == *key,
+        }
+    }
+
+    /// Multisig threshold check of admin instructions, the keys other than `signer` sign as remaining
+    /// accounts. Passes right away for the role member or the authority already checked on `signer`
+    pub fn require_cosigners(&self, role: Option<Role>, signer: &Signer, cosigners: &[AccountInfo]) -> Result<()> {
+        let signers: Vec<Pubkey> = std::iter::once(signer.key())
+            .chain(cosigners.iter().filter(|account| account.is_signer).map(|account| account.key()))
+            .collect();
+        require!(self.is_admin(role, &signers), FlashLoanError::MissingRole);
+
+        Ok(())
+    }
+
+    pub fn is_trusted_caller(&self, program_id: &Pubkey) -> bool {
+        self.trusted_callers.contains(program_id)
+    }
+
+    /// Pool fees must respect the fee cap and the discount must not exceed the regular fee
+    pub fn validate_pool_fees(&self, fee: &Fee, discounted_fee: &Fee) -> Result<()> {
+        require!(*fee <= self.max_fee, FlashLoanError::FeeAboveMax);
+        require!(discounted_fee <= fee, FlashLoanError::DiscountedFeeAboveFee);
+
+        Ok(())
+    }
+
+    /// The curve must respect the fee cap even when a loan takes the whole vault
+    pub fn validate_fee_curve(&self, curve: &FeeCurve) -> Result<()> {
+        require!(curve.kink <= Fee::SCALE, FlashLoanError::InvalidFeeCurve);
+        require!(curve.fee_at(Fee::SCALE) <= self.max_fee, FlashLoanError::FeeAboveMax);
+
+        Ok(())
+    }
+
+    /// Tiers must respect the fee cap and be sorted by strictly ascending `min_amount`
+    pub fn validate_fee_tiers(&self, fee_tiers: &[FeeTier]) -> Result<()> {
+        require!(fee_tiers.len() <= MAX_FEE_TIERS, FlashLoanError::InvalidFeeTiers);
+        require!(
+            fee_tiers.windows(2).all(|pair| pair[0].min_amount < pair[1].min_amount),
+            FlashLoanError::InvalidFeeTiers
+        );
+        for tier in fee_tiers {
+            require!(Fee::from_rate(tier.fee) <= self.max_fee, FlashLoanError::FeeAboveMax);
+        }
+
+        Ok(())
+    }
+}
+
+/// Admin duties that can be handed to keys other than the authority,
+/// an unassigned role is the default key nobody can sign for
+#[derive(Clone, Copy, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub enum Role {
+    PoolAdmin,
+    FeeManager,
+    Pauser,
+    VoucherIssuer,
+}
+
+/// M-of-N set of keys acting as the authority
+#[derive(Clone, Debug, Default, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct Multisig {
+    pub signers: Vec<Pubkey>,
+    pub threshold: u8,
+}
+
+impl Multisig {
+    const LEN: usize = 4 + 32*MAX_MULTISIG_SIGNERS + 1;
+
+    /// Distinct keys, at most `MAX_MULTISIG_SIGNERS`, and a threshold between one and their count
+    pub fn validate(&self) -> Result<()> {
+        let unique = self.signers.iter()
+            .enumerate()
+            .all(|(i, signer)| !self.signers[..i].contains(signer));
+        require!(
+            unique
+                && self.signers.len() <= MAX_MULTISIG_SIGNERS
+                && self.threshold > 0
+                && self.threshold as usize <= self.signers.len(),
+            FlashLoanError::InvalidMultisig
+        );
+
+        Ok(())
+    }
+
+    /// Whether at least `threshold` of the keys are among `signers`
+    pub fn approves(&self, signers: &[Pubkey]) -> bool {
+        self.signers.iter().filter(|key| signers.contains(key)).count() >= self.threshold as usize
+    }
+}
+
+#[account]
+#[derive(Default)]
+pub struct Pool {
+    /// Layout version, see `migration`
+    pub version: u8,
+    pub bump: u8,
+    pub borrowing: bool,
+    pub fee: Fee,
+    pub discounted_fee: Fee,
+    pub token_mint: Pubkey,
+    pub pool_token: Pubkey,
+    pub lp_token_mint: Pubkey,
+    /// Principal of the outstanding loan
+    pub loan_amount: u64,
+    /// Fee expected on top of the outstanding loan
+    pub loan_fee: u64,
+    /// pool_token balance before the outstanding loan was lent
+    pub pre_borrow_balance: u64,
+    /// Protocol share of the fees held by pool_token, not yet collected to the treasury
+    pub protocol_fees: u64,
+    /// Lowest fee charged for a loan regardless of its size
+    pub min_fee: u64,
+    /// Replaces the flat fee when set
+    pub fee_curve: Option<FeeCurve>,
+    /// Replace the flat fee and the curve for loans of at least their `min_amount`
+    pub fee_tiers: Vec<FeeTier>,
+    /// Merkle root of the `(borrower, fee)` partner rates
+    pub allowlist_root: Option<[u8; 32]>,
+    /// Discount for borrowers holding the governance mint
+    pub governance_discount: Option<GovernanceDiscount>,
+}
+
+impl Pool {
+    /// Bumped with every layout change, the previous layout then gets its struct in `migration`
+    pub const VERSION: u8 = 5;
+    const LEN: usize = 8 + 1 + 2 + 32*3 + Fee::LEN*2 + 8*3 + 8 + 8 + 1 + FeeCurve::LEN
+        + 4 + FeeTier::LEN*MAX_FEE_TIERS + 1 + 32 + 1 + GovernanceDiscount::LEN;
+
+    /// Partner rate of `borrower` proven against the allowlist root
+    pub fn allowlist_fee(&self, borrower: &Pubkey, proof: &AllowlistProof) -> Result<Fee> {
+        let root = self.allowlist_root.ok_or_else(|| error!(FlashLoanError::InvalidAllowlistProof))?;
+        require!(
+            merkle::verify(&proof.proof, &root, merkle::leaf(borrower, proof.fee)),
+            FlashLoanError::InvalidAllowlistProof
+        );
+
+        Ok(Fee::from_rate(proof.fee))
+    }
+
+    /// Index of the fee tier applying to a loan of `amount`, the one with the highest `min_amount` reached
+    pub fn fee_tier(&self, amount: u64) -> Option<u8> {
+        FeeTier::find(&self.fee_tiers, amount)
+    }
+
+    /// Governance discount of `borrower`, from the first of `accounts` that is a frozen token account
+    /// of the governance mint owned by the borrower
+    pub fn governance_fee(&self, borrower: &Pubkey, accounts: &[AccountInfo]) -> Option<Fee> {
+        let discount = self.governance_discount.as_ref()?;
+
+        let balance = accounts.iter()
+            .filter(|account| *account.owner == token::ID)
+            .filter_map(|account| Account::<TokenAccount>::try_from(account).ok())
+            .find(|token| token.mint == discount.mint && token.owner == *borrower && token.is_frozen())?
+            .amount;
+
+        discount.fee_for(balance)
+    }
+
+    /// Fee rate for a loan of `amount` out of a vault holding `balance`, from the matching tier,
+    /// else the curve, else the flat fee. A voucher `discount` applies when it is lower
+    pub fn borrow_fee(&self, amount: u64, balance: u64, discount: Option<Fee>) -> Fee {
+        let fee = match (self.fee_tier(amount), &self.fee_curve) {
+            (Some(tier), _) => Fee::from_rate(self.fee_tiers[tier as usize].fee),
+            (None, Some(curve)) => curve.fee_for(amount, balance),
+            (None, None) => self.fee,
+        };
+
+        match discount {
+            Some(discount) => fee.min(discount),
+            None => fee,
+        }
+    }
+
+    /// Whether `candidate` lowers the fee charged for a loan of `amount` out of a vault holding
+    /// `balance` below the fee with `discount` alone
+    pub fn lowers_fee(&self, amount: u64, balance: u64, discount: Option<Fee>, candidate: Fee, max_fee: &Fee) -> bool {
+        let fee = self.borrow_fee(amount, balance, discount);
+        let candidate_fee = self.borrow_fee(amount, balance, Some(candidate));
+
+        self.loan_fee_for(&candidate_fee, amount, max_fee) < self.loan_fee_for(&fee, amount, max_fee)
+    }
+
+    /// Fee charged for a loan of `amount` at `fee`, never less than min_fee
+    /// nor more than `max_fee` charges for it
+    pub fn loan_fee_for(&self, fee: &Fee, amount: u64, max_fee: &Fee) -> u64 {
+        fee.apply(amount).max(self.min_fee).min(max_fee.apply(amount))
+    }
+
+    /// Records a loan lent out of a vault holding `pre_borrow_balance`, locks the pool until repaid
+    fn open_loan(&mut self, amount: u64, fee: u64, pre_borrow_balance: u64) {
+        self.borrowing = true;
+        self.loan_amount = amount;
+        self.loan_fee = fee;
+        self.pre_borrow_balance = pre_borrow_balance;
+    }
+
+    /// Clears the outstanding loan, the vault must be back to at least its pre-borrow balance plus fee.
+    /// The `protocol_fee` share of the loan fee is accrued to the protocol
+    fn close_loan(&mut self, balance: u64, protocol_fee: &Fee) -> Result<()> {
+        let expected_balance = self.pre_borrow_balance
+            .checked_add(self.loan_fee)
+            .ok_or_else(|| error!(FlashLoanError::CalculationFailure))?;
+        require!(balance >= expected_balance, FlashLoanError::IncorrectRepay);
+
+        self.protocol_fees = self.protocol_fees
+            .checked_add(protocol_fee.apply(self.loan_fee))
+            .ok_or_else(|| error!(FlashLoanError::CalculationFailure))?;
+
+        self.borrowing = false;
+        self.loan_amount = 0;
+        self.loan_fee = 0;
+        self.pre_borrow_balance = 0;
+
+        Ok(())
+    }
+}
+
+/// How a fee amount is rounded to whole tokens, Floor is declared first so that
+/// for equal rates a Floor fee compares as lower than a Ceil one
+#[derive(
+    Clone, Copy, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum Rounding {
+    Floor,
+    Ceil,
+}
+
+impl Default for Rounding {
+    /// Fees are charged to borrowers, so splitting a loan must not make it cheaper
+    fn default() -> Self {
+        Rounding::Ceil
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct Fee {
+    /// Fraction of the amount charged, scaled by `Fee::SCALE`
+    pub rate: u64,
+    pub rounding: Rounding,
+}
+
+impl Fee {
+    const LEN: usize = 8 + 1;
+
+    /// Rate of 100%, one basis point is 100_000
+    pub const SCALE: u64 = 1_000_000_000;
+
+    /// 100%
+    pub const MAX: Fee = Fee { rate: Fee::SCALE, rounding: Rounding::Ceil };
+
+    pub fn from_rate(rate: u64) -> Self {
+        Self { rate, rounding: Rounding::default() }
+    }
+
+    pub fn from_basis_points(basis_points: u32) -> Self {
+        Self::from_rate(basis_points as u64 * (Fee::SCALE / 10_000))
+    }
+
+    pub fn with_rounding(self, rounding: Rounding) -> Self {
+        Self { rounding, ..self }
     }
 
+    /// Fee for `amount`, saturates for rates above 100%
     pub fn apply(&self, amount: u64) -> u64 {
-        // LMT no error possible
-        (amount as u128 * self.basis_points as u128 / 10_000_u128) as u64
+        let numerator = amount as u128 * self.rate as u128;
+        let floor = numerator / Fee::SCALE as u128;
+        let fee = match self.rounding {
+            Rounding::Ceil if floor * (Fee::SCALE as u128) < numerator => floor + 1,
+            _ => floor,
+        };
+        u64::try_from(fee).unwrap_or(u64::MAX)
+    }
+}
+
+/// Fee rising with the share of the vault a loan takes, `base_fee` up to `kink` utilization
+/// then increasing linearly by `slope` per whole vault borrowed above it.
+/// All fields are scaled by `Fee::SCALE`
+#[derive(Clone, Copy, Debug, Default, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct FeeCurve {
+    pub base_fee: u64,
+    pub kink: u64,
+    pub slope: u64,
+}
+
+impl FeeCurve {
+    const LEN: usize = 8*3;
+
+    /// Fee for a loan of `amount` out of a vault holding `balance`
+    pub fn fee_for(&self, amount: u64, balance: u64) -> Fee {
+        let utilization = if amount >= balance {
+            Fee::SCALE
+        } else {
+            // LMT no error possible, amount < balance
+            (amount as u128 * Fee::SCALE as u128 / balance as u128) as u64
+        };
+
+        self.fee_at(utilization)
+    }
+
+    /// Fee at `utilization` scaled by `Fee::SCALE`
+    pub fn fee_at(&self, utilization: u64) -> Fee {
+        let above_kink = utilization.saturating_sub(self.kink) as u128;
+        let increase = self.slope as u128 * above_kink / Fee::SCALE as u128;
+        let rate = u64::try_from(self.base_fee as u128 + increase).unwrap_or(u64::MAX);
+
+        Fee::from_rate(rate)
+    }
+}
+
+/// Allowlist entry of the borrower, the `fee` rate scaled by `Fee::SCALE` and its Merkle proof
+#[derive(Clone, Debug, Default, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct AllowlistProof {
+    pub fee: u64,
+    pub proof: Vec<[u8; 32]>,
+}
+
+/// Fee rate for loans of at least `min_amount`, scaled by `Fee::SCALE`
+#[derive(Clone, Copy, Debug, Default, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct FeeTier {
+    pub min_amount: u64,
+    pub fee: u64,
+}
+
+impl FeeTier {
+    const LEN: usize = 8 + 8;
+
+    /// Index of the tier with the highest `min_amount` reached by `amount`, tiers sorted ascending
+    fn find(tiers: &[FeeTier], amount: u64) -> Option<u8> {
+        tiers.iter()
+            .rposition(|tier| amount >= tier.min_amount)
+            .map(|index| index as u8)
+    }
+}
+
+/// Fee tiers by balance of the governance `mint`, a `min_amount` is the balance needed for the tier fee.
+/// Only locked balances count, held in token accounts frozen by the mint freeze authority: tokens
+/// borrowed within the transaction can't be paid back out of a frozen account, so a flash loan can't
+/// inflate the balance. Configure mints whose freeze authority is a locking or staking program that
+/// doesn't thaw accounts within the transaction that froze them
+#[derive(Clone, Debug, Default, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct GovernanceDiscount {
+    pub mint: Pubkey,
+    pub tiers: Vec<FeeTier>,
+}
+
+impl GovernanceDiscount {
+    const LEN: usize = 32 + 4 + FeeTier::LEN*MAX_FEE_TIERS;
+
+    /// Fee of the highest tier reached by `balance`
+    pub fn fee_for(&self, balance: u64) -> Option<Fee> {
+        FeeTier::find(&self.tiers, balance).map(|tier| Fee::from_rate(self.tiers[tier as usize].fee))
     }
 }
 
 #[account]
+#[derive(Default)]
 pub struct Voucher {
+    pub bump: u8,
     pub pool: Pubkey,
+    /// Borrower granted the discount, or the voucher mint for Nft vouchers
+    pub beneficiary: Pubkey,
+    /// Partner rate, the pool discounted_fee applies when unset
+    pub fee: Option<Fee>,
+    /// Unix timestamp from which the voucher is no longer accepted
+    pub expires_at: Option<i64>,
+    /// Borrows left
+    pub remaining_uses: Option<u64>,
+    /// Volume left to borrow
+    pub remaining_volume: Option<u64>,
+    pub kind: VoucherKind,
 }
 
 impl Voucher {
-    const LEN: usize = 8 + 32;
-
-    fn is_discounted_borrow(ctx: &Context<Borrow>) -> bool {
-        // Optional discount voucher account
-        if let Some(voucher) = ctx.remaining_accounts.get(0) {
-            voucher.owner == ctx.program_id &&
-                voucher.is_signer == true &&
-                voucher.is_writable == false &&
-                {
-                    let data = &*voucher.try_borrow_data().unwrap();
-                    if let Ok(voucher_data) = Voucher::try_deserialize(&mut &data[..]) {
-                        voucher_data.pool == ctx.accounts.pool.key()
-                    } else {
-                        false
-                    }
+    const LEN: usize = 8 + 1 + 32*2 + 1 + Fee::LEN + (1 + 8)*3 + 1;
+
+    /// Optional voucher account passed first in the remaining accounts, when the borrower is its
+    /// beneficiary, or for Nft vouchers holds the voucher token in the token account passed second.
+    /// A voucher with usage limits must be writable once redeemed
+    fn held_voucher<'info>(ctx: &Context<'_, '_, '_, 'info, Borrow<'info>>) -> Result<Option<Account<'info, Voucher>>> {
+        let voucher = match ctx.remaining_accounts.first() {
+            Some(voucher) if voucher.owner == ctx.program_id => voucher,
+            _ => return Ok(None),
+        };
+
+        let voucher_data = match Account::<Voucher>::try_from(voucher) {
+            Ok(voucher_data) if voucher_data.pool == ctx.accounts.pool.key() => voucher_data,
+            _ => return Ok(None),
+        };
+
+        let borrower = ctx.accounts.borrower.key();
+        let is_holder = match voucher_data.kind {
+            VoucherKind::Beneficiary => voucher_data.beneficiary == borrower,
+            VoucherKind::Nft => match ctx.remaining_accounts.get(1).map(Account::<TokenAccount>::try_from) {
+                Some(Ok(holder_token)) => {
+                    holder_token.mint == voucher_data.beneficiary
+                        && holder_token.owner == borrower
+                        && holder_token.amount == 1
                 }
-        } else {
-            false
+                _ => false,
+            },
+        };
+
+        Ok(if is_holder { Some(voucher_data) } else { None })
+    }
+
+    /// Sets the voucher fee and usage limits, the fee must not exceed the regular pool fee
+    fn set_terms(
+        &mut self,
+        pool: &Pool,
+        fee: Option<u64>,
+        expires_at: Option<i64>,
+        max_uses: Option<u64>,
+        max_total_volume: Option<u64>,
+    ) -> Result<()> {
+        let fee = fee.map(Fee::from_rate);
+        if let Some(fee) = &fee {
+            require!(*fee <= pool.fee, FlashLoanError::DiscountedFeeAboveFee);
+        }
+
+        self.fee = fee;
+        self.expires_at = expires_at;
+        self.remaining_uses = max_uses;
+        self.remaining_volume = max_total_volume;
+
+        Ok(())
+    }
+
+    fn is_limited(&self) -> bool {
+        self.remaining_uses.is_some() || self.remaining_volume.is_some()
+    }
+
+    /// Checks the voucher is still valid at `now` and counts a borrow of `amount` against its limits
+    fn redeem(&mut self, amount: u64, now: i64) -> Result<()> {
+        if let Some(expires_at) = self.expires_at {
+            require!(now < expires_at, FlashLoanError::VoucherExpired);
         }
+
+        if let Some(uses) = self.remaining_uses {
+            self.remaining_uses = Some(uses.checked_sub(1).ok_or_else(|| error!(FlashLoanError::VoucherExhausted))?);
+        }
+
+        if let Some(volume) = self.remaining_volume {
+            self.remaining_volume = Some(volume.checked_sub(amount).ok_or_else(|| error!(FlashLoanError::VoucherExhausted))?);
+        }
+
+        Ok(())
+    }
+}
+
+/// Who a voucher is granted to
+#[derive(Clone, Copy, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub enum VoucherKind {
+    /// The beneficiary key itself
+    Beneficiary,
+    /// Whoever holds the supply-1 token of the beneficiary mint
+    Nft,
+}
+
+impl Default for VoucherKind {
+    /// Vouchers issued before the kind existed are zero padded, they keep their meaning
+    fn default() -> Self {
+        VoucherKind::Beneficiary
     }
 }
 
 // -----------------------------------------------------------------------------------------------
 
+#[event]
+pub struct AuthorityTransferredEvent {
+    pub flashloan: Pubkey,
+    pub old_authority: Pubkey,
+    pub new_authority: Pubkey,
+}
+
+#[event]
+pub struct RoleUpdatedEvent {
+    pub flashloan: Pubkey,
+    pub role: Role,
+    pub old_member: Pubkey,
+    pub new_member: Pubkey,
+}
+
+#[event]
+pub struct MultisigUpdatedEvent {
+    pub flashloan: Pubkey,
+    pub old_multisig: Option<Multisig>,
+    pub new_multisig: Option<Multisig>,
+}
+
+#[event]
+pub struct PausedEvent {
+    pub flashloan: Pubkey,
+    pub paused: bool,
+}
+
+#[event]
+pub struct PoolUpdatedEvent {
+    pub pool: Pubkey,
+    pub old_fee: Fee,
+    pub new_fee: Fee,
+    pub old_discounted_fee: Fee,
+    pub new_discounted_fee: Fee,
+    pub old_min_fee: u64,
+    pub new_min_fee: u64,
+}
+
+#[event]
+pub struct FeeCurveUpdatedEvent {
+    pub pool: Pubkey,
+    pub old_fee_curve: Option<FeeCurve>,
+    pub new_fee_curve: Option<FeeCurve>,
+}
+
+#[event]
+pub struct FeeTiersUpdatedEvent {
+    pub pool: Pubkey,
+    pub old_fee_tiers: Vec<FeeTier>,
+    pub new_fee_tiers: Vec<FeeTier>,
+}
+
+#[event]
+pub struct AllowlistUpdatedEvent {
+    pub pool: Pubkey,
+    pub old_allowlist_root: Option<[u8; 32]>,
+    pub new_allowlist_root: Option<[u8; 32]>,
+}
+
+#[event]
+pub struct GovernanceDiscountUpdatedEvent {
+    pub pool: Pubkey,
+    pub old_governance_discount: Option<GovernanceDiscount>,
+    pub new_governance_discount: Option<GovernanceDiscount>,
+}
+
+#[event]
+pub struct ProtocolFeesCollectedEvent {
+    pub token_mint: Pubkey,
+    pub treasury_token: Pubkey,
+    pub amount: u64,
+}
+
 #[event]
 pub struct DepositEvent {
+    pub depositor: Pubkey,
     pub token_mint: Pubkey,
     pub token_amount: u64,
     pub lp_amount: u64,
@@ -617,14 +2195,27 @@ pub struct WithdrawEvent {
 
 #[event]
 pub struct BorrowEvent {
+    pub borrower: Pubkey,
     pub token_mint: Pubkey,
     pub amount: u64,
+    /// Index of the pool fee tier applied, if any
+    pub tier: Option<u8>,
 }
 
 #[event]
 pub struct RepayEvent {
+    pub borrower: Pubkey,
+    pub token_mint: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct FlashLoanEvent {
+    pub initiator: Pubkey,
+    pub receiver_program: Pubkey,
     pub token_mint: Pubkey,
     pub amount: u64,
+    pub fee: u64,
 }
 
 #[error_code]
@@ -635,4 +2226,426 @@ pub enum FlashLoanError {
     CpiRepay,
     Borrowing,
     CalculationFailure,
-}
\ No newline at end of file
+    NotBorrowing,
+    InvalidInstructionSysvar,
+    InvalidBatch,
+    AlreadyTrustedCaller,
+    UnknownTrustedCaller,
+    TooManyTrustedCallers,
+    MaxFeeTooHigh,
+    FeeAboveMax,
+    DiscountedFeeAboveFee,
+    ProtocolFeeTooHigh,
+    SlippageExceeded,
+    ZeroShares,
+    AlreadyMigrated,
+    InvalidFeeCurve,
+    InvalidFeeTiers,
+    VoucherExpired,
+    VoucherExhausted,
+    InvalidAllowlistProof,
+    NotPendingAuthority,
+    MissingRole,
+    Paused,
+    InvalidMultisig,
+    MinFeeTooHigh,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn fee_rounds_up_by_default() {
+        let fee = Fee::from_basis_points(10);
+
+        assert_eq!(fee.apply(0), 0);
+        assert_eq!(fee.apply(1), 1);
+        assert_eq!(fee.apply(999), 1);
+        assert_eq!(fee.apply(1_000), 1);
+        assert_eq!(fee.apply(1_001), 2);
+    }
+
+    #[test]
+    fn fee_rounds_down_on_request() {
+        let fee = Fee::from_basis_points(10).with_rounding(Rounding::Floor);
+
+        assert_eq!(fee.apply(999), 0);
+        assert_eq!(fee.apply(1_000), 1);
+        assert_eq!(fee.apply(1_999), 1);
+    }
+
+    #[test]
+    fn floor_fee_is_not_above_ceil_fee() {
+        let fee = Fee::from_basis_points(10);
+
+        assert!(fee.with_rounding(Rounding::Floor) <= fee);
+        assert!(fee <= Fee::MAX);
+    }
+
+    #[test]
+    fn fractional_basis_points() {
+        // 0.5 bps
+        let fee = Fee::from_rate(50_000);
+
+        assert_eq!(fee.apply(20_000), 1);
+        assert_eq!(fee.apply(20_001), 2);
+        assert_eq!(fee.with_rounding(Rounding::Floor).apply(39_999), 1);
+    }
+
+    #[test]
+    fn fee_at_u64_bounds() {
+        assert_eq!(Fee::MAX.apply(u64::MAX), u64::MAX);
+        assert_eq!(Fee::from_rate(1).apply(u64::MAX), u64::MAX / Fee::SCALE + 1);
+        assert_eq!(Fee::from_rate(1).with_rounding(Rounding::Floor).apply(u64::MAX), u64::MAX / Fee::SCALE);
+        assert_eq!(Fee::from_rate(u64::MAX).apply(u64::MAX), u64::MAX);
+        assert_eq!(Fee::default().apply(u64::MAX), 0);
+    }
+
+    #[test]
+    fn fee_curve_rises_above_kink() {
+        // 1 bps up to 50% utilization, then up to 101 bps for the whole vault
+        let curve = FeeCurve { base_fee: 100_000, kink: Fee::SCALE / 2, slope: 20_000_000 };
+
+        assert_eq!(curve.fee_for(5, 100), Fee::from_basis_points(1));
+        assert_eq!(curve.fee_for(50, 100), Fee::from_basis_points(1));
+        assert_eq!(curve.fee_for(75, 100), Fee::from_basis_points(51));
+        assert_eq!(curve.fee_for(100, 100), Fee::from_basis_points(101));
+        assert!(curve.fee_for(95, 100) > curve.fee_for(5, 100));
+    }
+
+    #[test]
+    fn fee_curve_treats_empty_vault_as_full() {
+        let curve = FeeCurve { base_fee: 0, kink: 0, slope: Fee::SCALE };
+
+        assert_eq!(curve.fee_for(1, 0), Fee::MAX);
+        assert_eq!(curve.fee_at(u64::MAX), Fee::from_rate(u64::MAX));
+    }
+
+    #[test]
+    fn voucher_discount_applies_to_the_curve() {
+        let mut pool = Pool {
+            fee: Fee::from_basis_points(10),
+            discounted_fee: Fee::from_basis_points(5),
+            ..Pool::default()
+        };
+        assert_eq!(pool.borrow_fee(100, 100, None), Fee::from_basis_points(10));
+        assert_eq!(pool.borrow_fee(100, 100, Some(pool.discounted_fee)), Fee::from_basis_points(5));
+
+        pool.fee_curve = Some(FeeCurve { base_fee: 100_000, kink: Fee::SCALE / 2, slope: 20_000_000 });
+        assert_eq!(pool.borrow_fee(5, 100, None), Fee::from_basis_points(1));
+        assert_eq!(pool.borrow_fee(5, 100, Some(pool.discounted_fee)), Fee::from_basis_points(1));
+        assert_eq!(pool.borrow_fee(100, 100, Some(pool.discounted_fee)), Fee::from_basis_points(5));
+        assert_eq!(pool.borrow_fee(100, 100, Some(Fee::from_basis_points(2))), Fee::from_basis_points(2));
+    }
+
+    #[test]
+    fn min_fee_is_capped_by_max_fee() {
+        let pool = Pool { fee: Fee::from_basis_points(10), min_fee: 50, ..Pool::default() };
+        let max_fee = Fee::from_basis_points(100);
+
+        assert_eq!(pool.loan_fee_for(&pool.fee, 100_000, &max_fee), 100);
+        assert_eq!(pool.loan_fee_for(&pool.fee, 10_000, &max_fee), 50);
+        assert_eq!(pool.loan_fee_for(&pool.fee, 1_000, &max_fee), 10);
+        assert_eq!(pool.loan_fee_for(&pool.fee, 0, &max_fee), 0);
+
+        let pool = Pool { min_fee: u64::MAX, ..pool };
+        assert_eq!(pool.loan_fee_for(&pool.fee, 1_000, &max_fee), 10);
+    }
+
+    #[test]
+    fn voucher_only_counts_when_it_lowers_the_fee() {
+        let pool = Pool {
+            fee: Fee::from_basis_points(10),
+            fee_tiers: vec![FeeTier { min_amount: 1_000_000, fee: 300_000 }],
+            ..Pool::default()
+        };
+        let max_fee = Fee::from_basis_points(100);
+        let voucher_fee = Fee::from_basis_points(5);
+
+        assert!(pool.lowers_fee(100_000, 1_000_000, None, voucher_fee, &max_fee));
+        assert!(!pool.lowers_fee(100_000, 1_000_000, Some(Fee::from_basis_points(2)), voucher_fee, &max_fee));
+        assert!(!pool.lowers_fee(100_000, 1_000_000, Some(voucher_fee), voucher_fee, &max_fee));
+        assert!(!pool.lowers_fee(100_000, 1_000_000, None, pool.fee, &max_fee));
+
+        // the tier already beats the voucher
+        assert!(!pool.lowers_fee(1_000_000, 10_000_000, None, voucher_fee, &max_fee));
+
+        // min_fee is charged either way
+        let pool = Pool { min_fee: 1_000, ..pool };
+        assert!(!pool.lowers_fee(100_000, 1_000_000, None, voucher_fee, &max_fee));
+    }
+
+    #[test]
+    fn fee_tiers_take_precedence() {
+        let pool = Pool {
+            fee: Fee::from_basis_points(10),
+            discounted_fee: Fee::from_basis_points(5),
+            fee_curve: Some(FeeCurve { base_fee: 800_000, kink: 0, slope: 0 }),
+            fee_tiers: vec![
+                FeeTier { min_amount: 1_000, fee: 600_000 },
+                FeeTier { min_amount: 1_000_000, fee: 300_000 },
+            ],
+            ..Pool::default()
+        };
+
+        assert_eq!(pool.fee_tier(999), None);
+        assert_eq!(pool.fee_tier(1_000), Some(0));
+        assert_eq!(pool.fee_tier(999_999), Some(0));
+        assert_eq!(pool.fee_tier(u64::MAX), Some(1));
+
+        assert_eq!(pool.borrow_fee(999, 10_000, None), Fee::from_basis_points(8));
+        assert_eq!(pool.borrow_fee(1_000, 10_000, None), Fee::from_basis_points(6));
+        assert_eq!(pool.borrow_fee(1_000_000, 10_000_000, None), Fee::from_basis_points(3));
+
+        // the voucher discount only applies when it beats the tier
+        assert_eq!(pool.borrow_fee(1_000, 10_000, Some(pool.discounted_fee)), Fee::from_basis_points(5));
+        assert_eq!(pool.borrow_fee(1_000_000, 10_000_000, Some(pool.discounted_fee)), Fee::from_basis_points(3));
+    }
+
+    #[test]
+    fn fee_tiers_must_ascend_under_the_cap() {
+        let flashloan = FlashLoan { max_fee: Fee::from_basis_points(100), ..FlashLoan::default() };
+        let tier = |min_amount, fee| FeeTier { min_amount, fee };
+
+        assert!(flashloan.validate_fee_tiers(&[]).is_ok());
+        assert!(flashloan.validate_fee_tiers(&[tier(1, 10_000_000), tier(2, 0)]).is_ok());
+        assert!(flashloan.validate_fee_tiers(&[tier(2, 0), tier(2, 0)]).is_err());
+        assert!(flashloan.validate_fee_tiers(&[tier(2, 0), tier(1, 0)]).is_err());
+        assert!(flashloan.validate_fee_tiers(&[tier(1, 10_000_001)]).is_err());
+        assert!(flashloan.validate_fee_tiers(&[tier(0, 0); MAX_FEE_TIERS + 1]).is_err());
+    }
+
+    #[test]
+    fn allowlist_fee_needs_a_valid_proof() {
+        let borrower = Pubkey::new_unique();
+        let partner = Pubkey::new_unique();
+        let borrower_leaf = merkle::leaf(&borrower, 200_000);
+        let partner_leaf = merkle::leaf(&partner, 100_000);
+
+        let mut pool = Pool::default();
+        let proof = AllowlistProof { fee: 200_000, proof: vec![partner_leaf] };
+        assert!(pool.allowlist_fee(&borrower, &proof).is_err());
+
+        pool.allowlist_root = Some(merkle::node(&borrower_leaf, &partner_leaf));
+        assert_eq!(pool.allowlist_fee(&borrower, &proof).unwrap(), Fee::from_basis_points(2));
+
+        let claimed = AllowlistProof { fee: 100_000, ..proof.clone() };
+        assert!(pool.allowlist_fee(&borrower, &claimed).is_err());
+        assert!(pool.allowlist_fee(&partner, &proof).is_err());
+    }
+
+    fn token_account(mint: Pubkey, owner: Pubkey, amount: u64, state: spl_token::state::AccountState) -> Vec<u8> {
+        use anchor_lang::solana_program::program_pack::Pack;
+
+        let mut data = vec![0; spl_token::state::Account::LEN];
+        spl_token::state::Account {
+            mint,
+            owner,
+            amount,
+            state,
+            ..Default::default()
+        }.pack_into_slice(&mut data);
+        data
+    }
+
+    #[test]
+    fn governance_discount_by_balance() {
+        let mint = Pubkey::new_unique();
+        let borrower = Pubkey::new_unique();
+        let pool = Pool {
+            governance_discount: Some(GovernanceDiscount {
+                mint,
+                tiers: vec![
+                    FeeTier { min_amount: 100, fee: 500_000 },
+                    FeeTier { min_amount: 1_000, fee: 200_000 },
+                ],
+            }),
+            ..Pool::default()
+        };
+
+        use spl_token::state::AccountState::{Frozen, Initialized};
+
+        let keys: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+        let mut data = [
+            token_account(Pubkey::new_unique(), borrower, 10_000, Frozen),
+            token_account(mint, Pubkey::new_unique(), 10_000, Frozen),
+            token_account(mint, borrower, 10_000, Initialized),
+            token_account(mint, borrower, 1_000, Frozen),
+        ];
+        let mut lamports = [0; 4];
+        let accounts: Vec<AccountInfo> = keys.iter()
+            .zip(data.iter_mut())
+            .zip(lamports.iter_mut())
+            .map(|((key, data), lamports)| AccountInfo::new(key, false, false, lamports, data, &token::ID, false, 0))
+            .collect();
+
+        assert_eq!(pool.governance_fee(&borrower, &accounts), Some(Fee::from_basis_points(2)));
+        assert_eq!(pool.governance_fee(&borrower, &accounts[..2]), None);
+
+        // a balance that can still move, like one just borrowed, doesn't count
+        assert_eq!(pool.governance_fee(&borrower, &accounts[..3]), None);
+        assert_eq!(pool.governance_fee(&Pubkey::new_unique(), &accounts), None);
+        assert_eq!(Pool::default().governance_fee(&borrower, &accounts), None);
+
+        let discount = pool.governance_discount.as_ref().unwrap();
+        assert_eq!(discount.fee_for(99), None);
+        assert_eq!(discount.fee_for(100), Some(Fee::from_basis_points(5)));
+        assert_eq!(discount.fee_for(999), Some(Fee::from_basis_points(5)));
+    }
+
+    #[test]
+    fn authority_holds_every_role() {
+        let authority = Pubkey::new_unique();
+        let fee_manager = Pubkey::new_unique();
+        let flashloan = FlashLoan { authority, fee_manager, ..FlashLoan::default() };
+
+        for role in [Role::PoolAdmin, Role::FeeManager, Role::Pauser, Role::VoucherIssuer] {
+            assert!(flashloan.is_admin(Some(role), &[authority]));
+        }
+        assert!(flashloan.is_admin(None, &[authority]));
+        assert!(flashloan.is_admin(Some(Role::FeeManager), &[fee_manager]));
+        assert!(!flashloan.is_admin(Some(Role::PoolAdmin), &[fee_manager]));
+        assert!(!flashloan.is_admin(None, &[fee_manager]));
+        assert!(!flashloan.is_admin(Some(Role::Pauser), &[Pubkey::new_unique()]));
+
+        assert!(flashloan.may_sign(None, &authority));
+        assert!(flashloan.may_sign(Some(Role::Pauser), &authority));
+        assert!(flashloan.may_sign(Some(Role::FeeManager), &fee_manager));
+        assert!(!flashloan.may_sign(Some(Role::PoolAdmin), &fee_manager));
+        assert!(!flashloan.may_sign(None, &fee_manager));
+    }
+
+    #[test]
+    fn multisig_stands_in_for_the_authority() {
+        let authority = Pubkey::new_unique();
+        let fee_manager = Pubkey::new_unique();
+        let keys: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+        let flashloan = FlashLoan {
+            authority,
+            fee_manager,
+            multisig: Some(Multisig { signers: keys.clone(), threshold: 2 }),
+            ..FlashLoan::default()
+        };
+
+        assert!(flashloan.is_admin(None, &keys[..2]));
+        assert!(flashloan.is_admin(Some(Role::PoolAdmin), &[keys[0], keys[2]]));
+        assert!(!flashloan.is_admin(None, &keys[..1]));
+        assert!(!flashloan.is_admin(None, &[keys[0], keys[0]]));
+        assert!(!flashloan.is_admin(None, &[authority, keys[1]]));
+        assert!(flashloan.is_admin(Some(Role::FeeManager), &[fee_manager]));
+
+        // any multisig key gets past the signer constraint, the handler checks the threshold
+        assert!(flashloan.may_sign(None, &keys[2]));
+        assert!(flashloan.may_sign(Some(Role::PoolAdmin), &keys[0]));
+        assert!(flashloan.may_sign(Some(Role::FeeManager), &fee_manager));
+        assert!(!flashloan.may_sign(None, &authority));
+    }
+
+    #[test]
+    fn multisig_validation() {
+        let keys: Vec<Pubkey> = (0..=MAX_MULTISIG_SIGNERS).map(|_| Pubkey::new_unique()).collect();
+        let multisig = |signers: &[Pubkey], threshold| Multisig { signers: signers.to_vec(), threshold };
+
+        assert!(multisig(&keys[..3], 2).validate().is_ok());
+        assert!(multisig(&keys[..MAX_MULTISIG_SIGNERS], 1).validate().is_ok());
+        assert!(multisig(&keys[..3], 0).validate().is_err());
+        assert!(multisig(&keys[..3], 4).validate().is_err());
+        assert!(multisig(&keys, 1).validate().is_err());
+        assert!(multisig(&[keys[0], keys[0]], 1).validate().is_err());
+    }
+
+    #[test]
+    fn unlimited_voucher_never_runs_out() {
+        let mut voucher = Voucher::default();
+
+        assert!(voucher.redeem(u64::MAX, i64::MAX).is_ok());
+        assert!(voucher.redeem(u64::MAX, i64::MAX).is_ok());
+        assert!(!voucher.is_limited());
+    }
+
+    #[test]
+    fn voucher_expires() {
+        let mut voucher = Voucher { expires_at: Some(100), ..Voucher::default() };
+
+        assert!(voucher.redeem(1, 99).is_ok());
+        assert!(voucher.redeem(1, 100).is_err());
+    }
+
+    #[test]
+    fn voucher_limits_are_counted_down() {
+        let mut voucher = Voucher {
+            remaining_uses: Some(2),
+            remaining_volume: Some(1_000),
+            ..Voucher::default()
+        };
+        assert!(voucher.is_limited());
+
+        voucher.redeem(600, 0).unwrap();
+        assert_eq!(voucher.remaining_uses, Some(1));
+        assert_eq!(voucher.remaining_volume, Some(400));
+
+        assert!(voucher.clone().redeem(401, 0).is_err());
+
+        voucher.redeem(400, 0).unwrap();
+        assert_eq!(voucher.remaining_uses, Some(0));
+        assert_eq!(voucher.remaining_volume, Some(0));
+
+        assert!(voucher.redeem(0, 0).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn fee_curve_is_monotonic(
+            amount in 0..u64::MAX,
+            balance: u64,
+            base_fee in 0..=Fee::SCALE,
+            kink in 0..=Fee::SCALE,
+            slope in 0..=Fee::SCALE,
+        ) {
+            let curve = FeeCurve { base_fee, kink, slope };
+
+            prop_assert!(curve.fee_for(amount, balance) <= curve.fee_for(amount + 1, balance));
+            prop_assert!(curve.fee_for(amount, balance) >= Fee::from_rate(base_fee));
+        }
+
+        #[test]
+        fn fee_matches_exact_division(amount: u64, rate in 0..=Fee::SCALE) {
+            let exact = amount as u128 * rate as u128;
+            let scale = Fee::SCALE as u128;
+
+            let floor = Fee::from_rate(rate).with_rounding(Rounding::Floor).apply(amount) as u128;
+            let ceil = Fee::from_rate(rate).apply(amount) as u128;
+
+            prop_assert!(floor * scale <= exact && exact < (floor + 1) * scale);
+            prop_assert!(ceil * scale >= exact && exact + scale > ceil * scale);
+            prop_assert!(ceil <= amount as u128);
+        }
+
+        #[test]
+        fn fee_is_monotonic_in_amount(amount in 0..u64::MAX, rate in 0..=Fee::SCALE) {
+            let fee = Fee::from_rate(rate);
+
+            prop_assert!(fee.apply(amount) <= fee.apply(amount + 1));
+        }
+
+        #[test]
+        fn splitting_a_loan_does_not_lower_the_fee(amount: u64, split: u64, rate in 0..=Fee::SCALE) {
+            let split = split % (amount / 2 + 1);
+            let fee = Fee::from_rate(rate);
+
+            prop_assert!(fee.apply(split) as u128 + fee.apply(amount - split) as u128 >= fee.apply(amount) as u128);
+        }
+
+        #[test]
+        fn basis_points_are_exact_rates(basis_points in 0..=10_000_u32, amount: u64) {
+            let fee = Fee::from_basis_points(basis_points);
+            let exact = amount as u128 * basis_points as u128;
+            let floor = exact / 10_000;
+            let expected = if floor * 10_000 < exact { floor + 1 } else { floor };
+
+            prop_assert_eq!(fee.apply(amount) as u128, expected);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Merkle allowlist of partner rates
+//!
+//! Leaves commit to a `(borrower, fee)` entry, inner nodes hash their children in sorted order so
+//! proofs carry no left/right flags. Leaves and nodes are hashed with distinct prefixes, a node can't
+//! be passed off as an entry.
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::keccak::hashv;
+
+const LEAF_PREFIX: [u8; 1] = [0];
+const NODE_PREFIX: [u8; 1] = [1];
+
+/// Leaf of the allowlist entry granting `borrower` the `fee` rate
+pub fn leaf(borrower: &Pubkey, fee: u64) -> [u8; 32] {
+    hashv(&[&LEAF_PREFIX, borrower.as_ref(), &fee.to_le_bytes()]).to_bytes()
+}
+
+/// Parent of two nodes, independent of their order
+pub fn node(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    hashv(&[&NODE_PREFIX, first, second]).to_bytes()
+}
+
+/// Whether `proof` links `leaf` to `root`
+pub fn verify(proof: &[[u8; 32]], root: &[u8; 32], leaf: [u8; 32]) -> bool {
+    proof.iter().fold(leaf, |hash, sibling| node(&hash, sibling)) == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<(Pubkey, u64)> {
+        (0..5).map(|i| (Pubkey::new_unique(), 100_000 * i)).collect()
+    }
+
+    /// Levels of the tree bottom up, an odd node is carried to the next level
+    fn tree(leaves: Vec<[u8; 32]>) -> Vec<Vec<[u8; 32]>> {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels.last().unwrap()
+                .chunks(2)
+                .map(|pair| if pair.len() == 2 { node(&pair[0], &pair[1]) } else { pair[0] })
+                .collect();
+            levels.push(next);
+        }
+        levels
+    }
+
+    fn proof(levels: &[Vec<[u8; 32]>], mut index: usize) -> Vec<[u8; 32]> {
+        let mut proof = Vec::new();
+        for level in &levels[..levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+        proof
+    }
+
+    #[test]
+    fn every_entry_is_proven() {
+        let entries = entries();
+        let levels = tree(entries.iter().map(|(borrower, fee)| leaf(borrower, *fee)).collect());
+        let root = levels.last().unwrap()[0];
+
+        for (index, (borrower, fee)) in entries.iter().enumerate() {
+            assert!(verify(&proof(&levels, index), &root, leaf(borrower, *fee)));
+        }
+    }
+
+    #[test]
+    fn other_entries_are_rejected() {
+        let entries = entries();
+        let levels = tree(entries.iter().map(|(borrower, fee)| leaf(borrower, *fee)).collect());
+        let root = levels.last().unwrap()[0];
+        let (borrower, fee) = entries[1];
+        let proof = proof(&levels, 1);
+
+        assert!(!verify(&proof, &root, leaf(&borrower, fee + 1)));
+        assert!(!verify(&proof, &root, leaf(&Pubkey::new_unique(), fee)));
+        assert!(!verify(&proof[1..], &root, leaf(&borrower, fee)));
+    }
+}
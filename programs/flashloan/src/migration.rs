@@ -0,0 +1,610 @@
+//! Account layout migrations
+//!
+//! Accounts in the original, unversioned layout are recognized by their exact size and their bump,
+//! later ones by their `version`. Each layout is read through its own struct: anchor writes accounts over their
+//! data without clearing the tail, so a field that shrank, like a shorter `Vec` or an `Option` set
+//! back to `None`, leaves stale bytes behind and whatever follows the stored layout is never read.
+//! Migrating grows the account in place, so pools keep their address and nothing deriving from it
+//! has to move.
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE;
+use anchor_lang::system_program;
+use anchor_lang::Discriminator;
+
+use crate::{FlashLoan, FlashLoanError, Fee, FeeCurve, FeeTier, Pool, FLASHLOAN_NAMESPACE};
+
+/// Fee in whole basis points, before `Fee::SCALE`, truncated when applied
+#[derive(Clone, Copy, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct FeeV0 {
+    pub basis_points: u32,
+}
+
+impl FeeV0 {
+    const LEN: usize = 4;
+
+    /// Same rate, fees round up from now on like every other fee
+    fn migrate(self) -> Fee {
+        Fee::from_basis_points(self.basis_points)
+    }
+}
+
+/// Original FlashLoan layout
+#[derive(Clone, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct FlashLoanV0 {
+    pub token_authority_bump: u8,
+    pub authority: Pubkey,
+}
+
+impl FlashLoanV0 {
+    const LEN: usize = 8 + 1 + 32;
+
+    /// Pool fees weren't capped, no trusted callers and no protocol share
+    fn migrate(self) -> FlashLoan {
+        FlashLoan {
+            version: FlashLoan::VERSION,
+            token_authority_bump: self.token_authority_bump,
+            authority: self.authority,
+            max_fee: Fee::MAX,
+            ..FlashLoan::default()
+        }
+    }
+}
+
+/// FlashLoan layout of version 1, fees scaled by `Fee::SCALE`
+#[derive(Clone, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct FlashLoanV1 {
+    pub version: u8,
+    pub token_authority_bump: u8,
+    pub authority: Pubkey,
+    pub trusted_callers: Vec<Pubkey>,
+    pub max_fee: Fee,
+    pub protocol_fee: Fee,
+    pub treasury: Pubkey,
+}
+
+impl FlashLoanV1 {
+    fn migrate(self) -> FlashLoan {
+        FlashLoan {
+            version: FlashLoan::VERSION,
+            token_authority_bump: self.token_authority_bump,
+            authority: self.authority,
+            trusted_callers: self.trusted_callers,
+            max_fee: self.max_fee,
+            protocol_fee: self.protocol_fee,
+            treasury: self.treasury,
+            ..FlashLoan::default()
+        }
+    }
+}
+
+/// FlashLoan layout of version 2, adds the pending authority
+#[derive(Clone, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct FlashLoanV2 {
+    pub v1: FlashLoanV1,
+    pub pending_authority: Option<Pubkey>,
+}
+
+impl FlashLoanV2 {
+    fn migrate(self) -> FlashLoan {
+        FlashLoan { pending_authority: self.pending_authority, ..self.v1.migrate() }
+    }
+}
+
+/// FlashLoan layout of version 3, adds the roles and the pause flag
+#[derive(Clone, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct FlashLoanV3 {
+    pub v2: FlashLoanV2,
+    pub pool_admin: Pubkey,
+    pub fee_manager: Pubkey,
+    pub pauser: Pubkey,
+    pub voucher_issuer: Pubkey,
+    pub paused: bool,
+}
+
+impl FlashLoanV3 {
+    fn migrate(self) -> FlashLoan {
+        FlashLoan {
+            pool_admin: self.pool_admin,
+            fee_manager: self.fee_manager,
+            pauser: self.pauser,
+            voucher_issuer: self.voucher_issuer,
+            paused: self.paused,
+            ..self.v2.migrate()
+        }
+    }
+}
+
+/// Original Pool layout
+#[derive(Clone, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct PoolV0 {
+    pub bump: u8,
+    pub borrowing: bool,
+    pub fee: FeeV0,
+    pub discounted_fee: FeeV0,
+    pub token_mint: Pubkey,
+    pub pool_token: Pubkey,
+    pub lp_token_mint: Pubkey,
+}
+
+impl PoolV0 {
+    const LEN: usize = 8 + 2 + FeeV0::LEN*2 + 32*3;
+
+    /// Loans never outlive their transaction, the loan state starts cleared
+    fn migrate(self) -> Pool {
+        Pool {
+            version: Pool::VERSION,
+            bump: self.bump,
+            borrowing: self.borrowing,
+            fee: self.fee.migrate(),
+            discounted_fee: self.discounted_fee.migrate(),
+            token_mint: self.token_mint,
+            pool_token: self.pool_token,
+            lp_token_mint: self.lp_token_mint,
+            ..Pool::default()
+        }
+    }
+}
+
+/// Pool layout of version 1, fees scaled by `Fee::SCALE`
+#[derive(Clone, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct PoolV1 {
+    pub version: u8,
+    pub bump: u8,
+    pub borrowing: bool,
+    pub fee: Fee,
+    pub discounted_fee: Fee,
+    pub token_mint: Pubkey,
+    pub pool_token: Pubkey,
+    pub lp_token_mint: Pubkey,
+    pub loan_amount: u64,
+    pub loan_fee: u64,
+    pub pre_borrow_balance: u64,
+    pub protocol_fees: u64,
+    pub min_fee: u64,
+}
+
+impl PoolV1 {
+    fn migrate(self) -> Pool {
+        Pool {
+            version: Pool::VERSION,
+            bump: self.bump,
+            borrowing: self.borrowing,
+            fee: self.fee,
+            discounted_fee: self.discounted_fee,
+            token_mint: self.token_mint,
+            pool_token: self.pool_token,
+            lp_token_mint: self.lp_token_mint,
+            loan_amount: self.loan_amount,
+            loan_fee: self.loan_fee,
+            pre_borrow_balance: self.pre_borrow_balance,
+            protocol_fees: self.protocol_fees,
+            min_fee: self.min_fee,
+            ..Pool::default()
+        }
+    }
+}
+
+/// Pool layout of version 2, adds the fee curve
+#[derive(Clone, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct PoolV2 {
+    pub v1: PoolV1,
+    pub fee_curve: Option<FeeCurve>,
+}
+
+impl PoolV2 {
+    fn migrate(self) -> Pool {
+        Pool { fee_curve: self.fee_curve, ..self.v1.migrate() }
+    }
+}
+
+/// Pool layout of version 3, adds the fee tiers
+#[derive(Clone, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct PoolV3 {
+    pub v2: PoolV2,
+    pub fee_tiers: Vec<FeeTier>,
+}
+
+impl PoolV3 {
+    fn migrate(self) -> Pool {
+        Pool { fee_tiers: self.fee_tiers, ..self.v2.migrate() }
+    }
+}
+
+/// Pool layout of version 4, adds the allowlist root
+#[derive(Clone, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct PoolV4 {
+    pub v3: PoolV3,
+    pub allowlist_root: Option<[u8; 32]>,
+}
+
+impl PoolV4 {
+    fn migrate(self) -> Pool {
+        Pool { allowlist_root: self.allowlist_root, ..self.v3.migrate() }
+    }
+}
+
+/// Reads the FlashLoan stored in `account` in an older layout converted to the current one
+pub fn load_outdated_flashloan(account: &AccountInfo, program_id: &Pubkey) -> Result<FlashLoan> {
+    if account.data_len() == FlashLoanV0::LEN {
+        // a later layout cut back to this size starts with its version where the bump was
+        let flashloan = load_layout::<FlashLoan, FlashLoanV0>(account)?;
+        let (_, bump) = Pubkey::find_program_address(&[account.key.as_ref(), FLASHLOAN_NAMESPACE.as_ref()], program_id);
+        require!(flashloan.token_authority_bump == bump, ErrorCode::ConstraintSeeds);
+
+        return Ok(flashloan.migrate());
+    }
+
+    match stored_version(account)? {
+        1 => Ok(load_layout::<FlashLoan, FlashLoanV1>(account)?.migrate()),
+        2 => Ok(load_layout::<FlashLoan, FlashLoanV2>(account)?.migrate()),
+        3 => Ok(load_layout::<FlashLoan, FlashLoanV3>(account)?.migrate()),
+        version if version >= FlashLoan::VERSION => Err(error!(FlashLoanError::AlreadyMigrated)),
+        _ => Err(error!(ErrorCode::AccountDidNotDeserialize)),
+    }
+}
+
+/// Reads the Pool stored in `account` in an older layout converted to the current one,
+/// the account must be the pool PDA of `flashloan`
+pub fn load_outdated_pool(account: &AccountInfo, flashloan: &Pubkey, program_id: &Pubkey) -> Result<Pool> {
+    let pool = if account.data_len() == PoolV0::LEN {
+        load_layout::<Pool, PoolV0>(account)?.migrate()
+    } else {
+        match stored_version(account)? {
+            1 => load_layout::<Pool, PoolV1>(account)?.migrate(),
+            2 => load_layout::<Pool, PoolV2>(account)?.migrate(),
+            3 => load_layout::<Pool, PoolV3>(account)?.migrate(),
+            4 => load_layout::<Pool, PoolV4>(account)?.migrate(),
+            version if version >= Pool::VERSION => return Err(error!(FlashLoanError::AlreadyMigrated)),
+            _ => return Err(error!(ErrorCode::AccountDidNotDeserialize)),
+        }
+    };
+
+    let pool_address = Pubkey::create_program_address(
+        &[flashloan.as_ref(), pool.token_mint.as_ref(), &[pool.bump]],
+        program_id,
+    ).map_err(|_| error!(ErrorCode::ConstraintSeeds))?;
+    require!(*account.key == pool_address, ErrorCode::ConstraintSeeds);
+
+    Ok(pool)
+}
+
+/// Reads an account of type `A` stored in layout `L`, the bytes past the layout are left alone
+fn load_layout<A: Discriminator, L: AnchorDeserialize>(account: &AccountInfo) -> Result<L> {
+    let data = account.try_borrow_data()?;
+    require!(data.len() > 8 && data[..8] == A::discriminator(), ErrorCode::AccountDiscriminatorMismatch);
+
+    Ok(L::deserialize(&mut &data[8..])?)
+}
+
+/// Version of a versioned account, the byte following the discriminator
+fn stored_version(account: &AccountInfo) -> Result<u8> {
+    let data = account.try_borrow_data()?;
+    data.get(8).copied().ok_or_else(|| error!(ErrorCode::AccountDidNotDeserialize))
+}
+
+/// Grows `account` to fit `value`, paying the extra rent from `payer`, and writes it
+pub fn store<'info, A: AccountSerialize>(
+    account: &AccountInfo<'info>,
+    value: &A,
+    len: usize,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    let rent = Rent::get()?.minimum_balance(len).saturating_sub(account.lamports());
+    if rent > 0 {
+        let transfer_ctx = CpiContext::new(
+            system_program.clone(),
+            system_program::Transfer {
+                from: payer.clone(),
+                to: account.clone(),
+            },
+        );
+        system_program::transfer(transfer_ctx, rent)?;
+    }
+
+    grow(account, len)?;
+    require!(account.data_len() == len, ErrorCode::AccountDidNotSerialize);
+
+    let mut data = account.try_borrow_mut_data()?;
+    let mut writer: &mut [u8] = &mut data;
+    value.try_serialize(&mut writer)
+}
+
+/// In place `AccountInfo::realloc` of later solana-program releases, anchor 0.23 pins solana-program
+/// 1.8 which doesn't have it. The runtime reserves MAX_PERMITTED_DATA_INCREASE bytes after the data
+/// of every account, and reads the serialized length back once the program returns only where the
+/// `do_support_realloc` feature is active. On a cluster without it the new length is ignored and the
+/// account keeps its old size, the bump checks of the original layouts then refuse to migrate it
+/// again. `tests/migration.ts` covers it on the local validator
+fn grow(account: &AccountInfo, len: usize) -> Result<()> {
+    let mut data = account.try_borrow_mut_data()?;
+    require!(
+        len >= data.len() && len - data.len() <= MAX_PERMITTED_DATA_INCREASE,
+        ErrorCode::AccountDidNotSerialize
+    );
+
+    let old_len = data.len();
+    let ptr = data.as_mut_ptr();
+    // SAFETY: the serialized length precedes the data and the reserved bytes follow it,
+    // both only exist for accounts handed to the program by the runtime
+    unsafe {
+        *(ptr.offset(-8) as *mut u64) = len as u64;
+        *data = std::slice::from_raw_parts_mut(ptr, len);
+    }
+    data[old_len..].fill(0);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::{Rounding, MAX_TRUSTED_CALLERS};
+
+    /// Account info of `data` owned by the program
+    fn account<'a>(key: &'a Pubkey, lamports: &'a mut u64, data: &'a mut [u8]) -> AccountInfo<'a> {
+        AccountInfo::new(key, false, true, lamports, data, &crate::ID, false, 0)
+    }
+
+    #[test]
+    fn legacy_fees_keep_their_value() {
+        let fee = FeeV0 { basis_points: 10 }.migrate();
+
+        assert_eq!(fee, Fee::from_rate(1_000_000));
+        assert_eq!(fee.apply(1_000), 1);
+        assert_eq!(fee.apply(1_001), 2);
+    }
+
+    #[test]
+    fn legacy_pool_is_converted() {
+        let flashloan = Pubkey::new_unique();
+        let token_mint = Pubkey::new_unique();
+        let pool_token = Pubkey::new_unique();
+        let lp_token_mint = Pubkey::new_unique();
+        let (address, bump) = Pubkey::find_program_address(&[flashloan.as_ref(), token_mint.as_ref()], &crate::ID);
+
+        // original layout: bump, borrowing, fee and discounted fee in u32 basis points, then the keys
+        let mut data = Pool::discriminator().to_vec();
+        data.extend_from_slice(&[bump, 0]);
+        data.extend_from_slice(&10_u32.to_le_bytes());
+        data.extend_from_slice(&5_u32.to_le_bytes());
+        data.extend_from_slice(token_mint.as_ref());
+        data.extend_from_slice(pool_token.as_ref());
+        data.extend_from_slice(lp_token_mint.as_ref());
+        assert_eq!(data.len(), 114);
+
+        let mut lamports = 0;
+        let info = account(&address, &mut lamports, &mut data);
+
+        let pool = load_outdated_pool(&info, &flashloan, &crate::ID).unwrap();
+        assert_eq!(pool.version, Pool::VERSION);
+        assert_eq!(pool.bump, bump);
+        assert!(!pool.borrowing);
+        assert_eq!(pool.fee, Fee::from_basis_points(10));
+        assert_eq!(pool.discounted_fee, Fee::from_basis_points(5));
+        assert_eq!(pool.token_mint, token_mint);
+        assert_eq!(pool.pool_token, pool_token);
+        assert_eq!(pool.lp_token_mint, lp_token_mint);
+        assert_eq!(pool.loan_amount, 0);
+        assert_eq!(pool.protocol_fees, 0);
+        assert_eq!(pool.min_fee, 0);
+
+        let mut migrated = Vec::new();
+        pool.try_serialize(&mut migrated).unwrap();
+        assert!(migrated.len() <= Pool::LEN);
+
+        assert!(load_outdated_pool(&info, &Pubkey::new_unique(), &crate::ID).is_err());
+
+        // cut back to the original size, the version sits where the bump was
+        migrated.truncate(PoolV0::LEN);
+        let info = account(&address, &mut lamports, &mut migrated);
+        assert!(load_outdated_pool(&info, &flashloan, &crate::ID).is_err());
+    }
+
+    fn pool_v1(version: u8, bump: u8, token_mint: Pubkey) -> PoolV1 {
+        PoolV1 {
+            version,
+            bump,
+            borrowing: false,
+            fee: Fee::from_basis_points(10),
+            discounted_fee: Fee::from_basis_points(5),
+            token_mint,
+            pool_token: Pubkey::new_unique(),
+            lp_token_mint: Pubkey::new_unique(),
+            loan_amount: 0,
+            loan_fee: 0,
+            pre_borrow_balance: 0,
+            protocol_fees: 42,
+            min_fee: 7,
+        }
+    }
+
+    /// Writes each of `layouts` of an `A` over the same `len` bytes, like `Account::exit` after every update
+    fn overwritten<A: Discriminator, L: AnchorSerialize>(len: usize, layouts: &[L]) -> Vec<u8> {
+        let mut data = vec![0; len];
+        for layout in layouts {
+            let mut writer = &mut data[..];
+            writer.write_all(&A::discriminator()).unwrap();
+            layout.serialize(&mut writer).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn versioned_pool_is_converted() {
+        let flashloan = Pubkey::new_unique();
+        let token_mint = Pubkey::new_unique();
+        let (address, bump) = Pubkey::find_program_address(&[flashloan.as_ref(), token_mint.as_ref()], &crate::ID);
+        let curve = FeeCurve { base_fee: 50_000, kink: 500_000_000, slope: 10_000_000 };
+
+        let v2 = PoolV2 { v1: pool_v1(2, bump, token_mint), fee_curve: Some(curve) };
+        let mut data = overwritten::<Pool, _>(Pool::LEN, &[v2]);
+        let mut lamports = 0;
+        let info = account(&address, &mut lamports, &mut data);
+
+        let migrated = load_outdated_pool(&info, &flashloan, &crate::ID).unwrap();
+        assert_eq!(migrated.version, Pool::VERSION);
+        assert_eq!(migrated.fee, Fee::from_basis_points(10));
+        assert_eq!(migrated.token_mint, token_mint);
+        assert_eq!(migrated.protocol_fees, 42);
+        assert_eq!(migrated.min_fee, 7);
+        assert_eq!(migrated.fee_curve, Some(curve));
+        assert!(migrated.fee_tiers.is_empty());
+        assert_eq!(migrated.allowlist_root, None);
+        assert_eq!(migrated.governance_discount, None);
+
+        assert!(load_outdated_pool(&info, &Pubkey::new_unique(), &crate::ID).is_err());
+    }
+
+    #[test]
+    fn stale_bytes_of_shrunk_fields_are_ignored() {
+        let flashloan = Pubkey::new_unique();
+        let token_mint = Pubkey::new_unique();
+        let (address, bump) = Pubkey::find_program_address(&[flashloan.as_ref(), token_mint.as_ref()], &crate::ID);
+        let tiers = [FeeTier { min_amount: 1_000, fee: 500_000 }, FeeTier { min_amount: 2_000, fee: 200_000 }];
+        let v3 = |fee_tiers: &[FeeTier]| PoolV3 {
+            v2: PoolV2 { v1: pool_v1(3, bump, token_mint), fee_curve: None },
+            fee_tiers: fee_tiers.to_vec(),
+        };
+
+        // the tier table went from two entries to one, the second one is still in the data
+        let mut data = overwritten::<Pool, _>(Pool::LEN, &[v3(&tiers), v3(&tiers[..1])]);
+        let mut lamports = 0;
+        let info = account(&address, &mut lamports, &mut data);
+
+        let migrated = load_outdated_pool(&info, &flashloan, &crate::ID).unwrap();
+        assert_eq!(migrated.fee_tiers, tiers[..1]);
+        assert_eq!(migrated.allowlist_root, None);
+        assert_eq!(migrated.governance_discount, None);
+
+        // the allowlist root was removed, its 32 bytes are still in the data
+        let v4 = |allowlist_root| PoolV4 { v3: v3(&[]), allowlist_root };
+        let mut data = overwritten::<Pool, _>(Pool::LEN, &[v4(Some([7; 32])), v4(None)]);
+        let mut lamports = 0;
+        let info = account(&address, &mut lamports, &mut data);
+
+        let migrated = load_outdated_pool(&info, &flashloan, &crate::ID).unwrap();
+        assert_eq!(migrated.allowlist_root, None);
+        assert_eq!(migrated.governance_discount, None);
+    }
+
+    #[test]
+    fn current_pool_is_not_migrated_again() {
+        let flashloan = Pubkey::new_unique();
+        let token_mint = Pubkey::new_unique();
+        let (address, bump) = Pubkey::find_program_address(&[flashloan.as_ref(), token_mint.as_ref()], &crate::ID);
+        let pool = Pool { version: Pool::VERSION, bump, token_mint, ..Pool::default() };
+
+        let mut data = vec![0; Pool::LEN];
+        pool.try_serialize(&mut &mut data[..]).unwrap();
+        let mut lamports = 0;
+        let info = account(&address, &mut lamports, &mut data);
+
+        assert!(load_outdated_pool(&info, &flashloan, &crate::ID).is_err());
+    }
+
+    #[test]
+    fn legacy_flashloan_is_converted() {
+        let authority = Pubkey::new_unique();
+
+        // original layout: token authority bump and authority
+        let mut data = FlashLoan::discriminator().to_vec();
+        data.push(254);
+        data.extend_from_slice(authority.as_ref());
+        assert_eq!(data.len(), 41);
+
+        let key = Pubkey::new_unique();
+        let (_, bump) = Pubkey::find_program_address(&[key.as_ref(), FLASHLOAN_NAMESPACE.as_ref()], &crate::ID);
+        data[8] = bump;
+        let mut lamports = 0;
+        let info = account(&key, &mut lamports, &mut data);
+
+        let flashloan = load_outdated_flashloan(&info, &crate::ID).unwrap();
+        assert_eq!(flashloan.version, FlashLoan::VERSION);
+        assert_eq!(flashloan.token_authority_bump, bump);
+        assert_eq!(flashloan.authority, authority);
+        assert_eq!(flashloan.max_fee, Fee::MAX);
+        assert_eq!(flashloan.protocol_fee.rate, 0);
+        assert!(flashloan.trusted_callers.is_empty());
+        assert_eq!(flashloan.pending_authority, None);
+
+        let mut migrated = Vec::new();
+        flashloan.try_serialize(&mut migrated).unwrap();
+        assert!(migrated.len() <= FlashLoan::LEN);
+
+        // cut back to the original size, the version sits where the bump was
+        migrated.truncate(FlashLoanV0::LEN);
+        let info = account(&key, &mut lamports, &mut migrated);
+        assert!(load_outdated_flashloan(&info, &crate::ID).is_err());
+    }
+
+    fn flashloan_v1(version: u8, trusted_callers: &[Pubkey], treasury: Pubkey) -> FlashLoanV1 {
+        FlashLoanV1 {
+            version,
+            token_authority_bump: 255,
+            authority: Pubkey::new_unique(),
+            trusted_callers: trusted_callers.to_vec(),
+            max_fee: Fee::from_basis_points(100),
+            protocol_fee: Fee::from_basis_points(10).with_rounding(Rounding::Floor),
+            treasury,
+        }
+    }
+
+    #[test]
+    fn versioned_flashloan_is_converted() {
+        let pending = Pubkey::new_unique();
+        let v1 = flashloan_v1(2, &[Pubkey::new_unique(); MAX_TRUSTED_CALLERS], Pubkey::new_unique());
+        let v2 = FlashLoanV2 { v1: v1.clone(), pending_authority: Some(pending) };
+        let mut data = overwritten::<FlashLoan, _>(FlashLoan::LEN, &[v2]);
+
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let info = account(&key, &mut lamports, &mut data);
+
+        let migrated = load_outdated_flashloan(&info, &crate::ID).unwrap();
+        assert_eq!(migrated.version, FlashLoan::VERSION);
+        assert_eq!(migrated.authority, v1.authority);
+        assert_eq!(migrated.trusted_callers, v1.trusted_callers);
+        assert_eq!(migrated.protocol_fee, v1.protocol_fee);
+        assert_eq!(migrated.treasury, v1.treasury);
+        assert_eq!(migrated.pending_authority, Some(pending));
+        assert_eq!(migrated.pool_admin, Pubkey::default());
+        assert!(!migrated.paused);
+        assert_eq!(migrated.multisig, None);
+    }
+
+    #[test]
+    fn removed_trusted_caller_leaves_no_pending_authority() {
+        // the treasury bytes stay behind the shorter caller list, their first one is non zero
+        let callers = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let treasury = Pubkey::new_from_array([1; 32]);
+        let v2 = |callers: &[Pubkey]| FlashLoanV2 { v1: flashloan_v1(2, callers, treasury), pending_authority: None };
+        let mut data = overwritten::<FlashLoan, _>(FlashLoan::LEN, &[v2(&callers), v2(&callers[..1])]);
+
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let info = account(&key, &mut lamports, &mut data);
+
+        let migrated = load_outdated_flashloan(&info, &crate::ID).unwrap();
+        assert_eq!(migrated.trusted_callers, callers[..1]);
+        assert_eq!(migrated.treasury, treasury);
+        assert_eq!(migrated.pending_authority, None);
+        assert_eq!(migrated.pool_admin, Pubkey::default());
+        assert!(!migrated.paused);
+        assert_eq!(migrated.multisig, None);
+    }
+
+    #[test]
+    fn current_flashloan_is_not_migrated_again() {
+        let flashloan = FlashLoan { version: FlashLoan::VERSION, ..FlashLoan::default() };
+        let mut data = vec![0; FlashLoan::LEN];
+        flashloan.try_serialize(&mut &mut data[..]).unwrap();
+
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let info = account(&key, &mut lamports, &mut data);
+
+        assert!(load_outdated_flashloan(&info, &crate::ID).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+//! Flash loan receiver interface
+//!
+//! Programs taking loans through `flash_loan` implement an `on_flash_loan` instruction,
+//! ERC-3156 style. In anchor it is declared as
+//!
+//! ```ignore
+//! pub fn on_flash_loan(
+//!     ctx: Context<OnFlashLoan>,
+//!     initiator: Pubkey,
+//!     token_mint: Pubkey,
+//!     amount: u64,
+//!     fee: u64,
+//!     data: Vec<u8>,
+//! ) -> Result<()>
+//! ```
+//!
+//! The receiver gets the remaining accounts of `flash_loan` as its accounts and has to transfer
+//! `amount + fee` back to the pool token account before returning.
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use sha2_const::Sha256;
+
+/// Sha256("global:on_flash_loan")[..8]
+pub const ON_FLASH_LOAN_SIGHASH: [u8; 32] = Sha256::new()
+    .update(b"global:on_flash_loan")
+    .finalize();
+
+/// Arguments of the receiver `on_flash_loan` instruction
+#[derive(Clone, Debug, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct OnFlashLoanArgs {
+    /// Signer who initiated the loan
+    pub initiator: Pubkey,
+    pub token_mint: Pubkey,
+    pub amount: u64,
+    /// Fee to be paid on top of `amount`
+    pub fee: u64,
+    /// Opaque data forwarded from `flash_loan`
+    pub data: Vec<u8>,
+}
+
+/// Builds the `on_flash_loan` instruction for `receiver_program`, forwarding `accounts` as they are
+pub fn on_flash_loan_instruction(
+    receiver_program: Pubkey,
+    accounts: &[AccountInfo],
+    args: &OnFlashLoanArgs,
+) -> Result<Instruction> {
+    let mut data = ON_FLASH_LOAN_SIGHASH[..8].to_vec();
+    args.serialize(&mut data)?;
+
+    let accounts = accounts.iter()
+        .map(|account| {
+            if account.is_writable {
+                AccountMeta::new(*account.key, account.is_signer)
+            } else {
+                AccountMeta::new_readonly(*account.key, account.is_signer)
+            }
+        })
+        .collect();
+
+    Ok(Instruction {
+        program_id: receiver_program,
+        accounts,
+        data,
+    })
+}
@@ -2,10 +2,12 @@ import * as anchor from "@project-serum/anchor";
 import { Program, web3, BN, Spl } from "@project-serum/anchor";
 import {PublicKey, Keypair} from '@solana/web3.js';
 import { Flashloan } from "../target/types/flashloan";
+import { FlashloanReceiver } from "../target/types/flashloan_receiver";
 
 import { expect } from 'chai';
 import * as chai from 'chai';
 import chaiAsPromised from 'chai-as-promised';
+import { keccak_256 } from 'js-sha3';
 chai.use(chaiAsPromised);
 
 describe("flashloan", () => {
@@ -13,6 +15,7 @@ describe("flashloan", () => {
   anchor.setProvider(anchor.Provider.env());
   const provider = anchor.getProvider();
   const program = anchor.workspace.Flashloan as Program<Flashloan>;
+  const receiver = anchor.workspace.FlashloanReceiver as Program<FlashloanReceiver>;
   const spl_token = Spl.token();
 
   const flashloan = Keypair.generate();
@@ -23,9 +26,9 @@ describe("flashloan", () => {
   const token2 = Keypair.generate();
   const lp_token1 = Keypair.generate();
 
-  async function create_mint(mint: Keypair, mint_authority: PublicKey) {
+  async function create_mint(mint: Keypair, mint_authority: PublicKey, freeze_authority: PublicKey | null = null) {
     await spl_token.methods
-      .initializeMint(9, mint_authority, null)
+      .initializeMint(9, mint_authority, freeze_authority)
       .accounts({
         mint: mint.publicKey,
         rent: web3.SYSVAR_RENT_PUBKEY,
@@ -83,6 +86,87 @@ describe("flashloan", () => {
     return lp_token_mint;
   }
 
+  async function find_voucher(pool: PublicKey, beneficiary: PublicKey) {
+    const [voucher, _nonce] = await PublicKey.findProgramAddress(
+      [pool.toBuffer(), Buffer.from(anchor.utils.bytes.utf8.encode("voucher")), beneficiary.toBuffer()],
+      program.programId
+    );
+
+    return voucher;
+  }
+
+  async function mint_voucher(pool: PublicKey, fee: BN | null, expiresAt: BN | null, maxUses: BN | null) {
+    const voucher = await find_voucher(pool, provider.wallet.publicKey);
+
+    await program.methods.mintVoucher(provider.wallet.publicKey, fee, expiresAt, maxUses, null)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        voucherIssuer: authority.publicKey,
+        pool,
+        voucher,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([authority])
+      .rpc();
+
+    return voucher;
+  }
+
+  async function revoke_voucher(pool: PublicKey, voucher: PublicKey) {
+    await program.methods.revokeVoucher()
+      .accounts({
+        flashloan: flashloan.publicKey,
+        voucherIssuer: authority.publicKey,
+        pool,
+        voucher,
+      })
+      .signers([authority])
+      .rpc();
+  }
+
+  async function borrow_with_voucher(pool: PublicKey, voucher: PublicKey, repayAmount: number) {
+    const borrowInstruction = await program.methods
+      .borrow(new BN(100 * web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pool,
+        userToken: token2.publicKey,
+        borrower: provider.wallet.publicKey,
+        instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+      }).instruction();
+
+    // Add voucher account
+    borrowInstruction.keys.push({
+      isSigner: false,
+      isWritable: true,
+      pubkey: voucher,
+    })
+
+    await program.methods
+      .repay(new BN(repayAmount * web3.LAMPORTS_PER_SOL))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pool,
+        userToken: token2.publicKey,
+        borrower: provider.wallet.publicKey,
+        instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+      })
+      .preInstructions([borrowInstruction])
+      .rpc();
+  }
+
+  async function top_up_fees() {
+    await spl_token.methods
+      .mintTo(new BN(0.1 * web3.LAMPORTS_PER_SOL))
+      .accounts(
+        {
+          mint: mint.publicKey,
+          to: token2.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+      .rpc();
+  }
+
   before(async () => {
     await create_mint(mint, provider.wallet.publicKey);
     await create_token(token1, mint.publicKey, provider.wallet.publicKey);
@@ -99,7 +183,7 @@ describe("flashloan", () => {
       .rpc();
 
     await program.methods
-      .initialize()
+      .initialize(new BN(10_000_000))
       .accounts({
         flashloan: flashloan.publicKey,
         authority: authority.publicKey,
@@ -108,45 +192,33 @@ describe("flashloan", () => {
       .rpc();
 
     await program.methods
-      .addPool(10, 5)
+      .addPool(new BN(1_000_000), new BN(500_000))
       .accounts({
         flashloan: flashloan.publicKey,
-        authority: authority.publicKey,
+        poolAdmin: authority.publicKey,
         tokenMint: mint.publicKey,
       })
       .signers([authority])
       .rpc();
 
     const pool = await find_pool(flashloan.publicKey, mint.publicKey);
-    const [tokenAuthority, _nonce] = await find_token_authority(flashloan.publicKey);
     const lpTokenMint = await find_lp_token_mint(flashloan.publicKey, mint.publicKey)
     await create_token(lp_token1, lpTokenMint, provider.wallet.publicKey);
 
     await program.methods
-      .deposit(new BN(1000 * web3.LAMPORTS_PER_SOL))
+      .deposit(new BN(1000 * web3.LAMPORTS_PER_SOL), new BN(1000 * web3.LAMPORTS_PER_SOL))
       .accounts({
         flashloan: flashloan.publicKey,
         pool,
+        depositor: provider.wallet.publicKey,
         userToken: token1.publicKey,
         userLpToken: lp_token1.publicKey,
       })
-      .preInstructions(
-        [
-          await spl_token.methods
-            .approve(new BN(1000 * web3.LAMPORTS_PER_SOL))
-            .accounts({
-              source: token1.publicKey,
-              delegate: tokenAuthority,
-              authority: provider.wallet.publicKey
-            }).instruction()
-        ]
-      )
       .rpc();
   })
 
   it("Should borrow and repay", async () => {
     const pool = await find_pool(flashloan.publicKey, mint.publicKey);
-    const [tokenAuthority, _nonce] = await find_token_authority(flashloan.publicKey);
     const poolToken = await find_pool_token(flashloan.publicKey, mint.publicKey);
 
     let poolTokenAccount = await spl_token.account.token.fetch(poolToken);
@@ -163,24 +235,14 @@ describe("flashloan", () => {
       .rpc();
 
     await program.methods
-      .borrow(new BN(100 * web3.LAMPORTS_PER_SOL))
+      .borrow(new BN(100 * web3.LAMPORTS_PER_SOL), null)
       .accounts({
         flashloan: flashloan.publicKey,
         pool,
         userToken: token2.publicKey,
+        borrower: provider.wallet.publicKey,
         instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
       })
-      .preInstructions(
-        [
-          await spl_token.methods
-            .approve(new BN(101 * web3.LAMPORTS_PER_SOL))
-            .accounts({
-              source: token2.publicKey,
-              delegate: tokenAuthority,
-              authority: provider.wallet.publicKey
-            }).instruction()
-        ]
-      )
       .postInstructions(
         [
           await program.methods
@@ -189,6 +251,7 @@ describe("flashloan", () => {
               flashloan: flashloan.publicKey,
               pool,
               userToken: token2.publicKey,
+              borrower: provider.wallet.publicKey,
               instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
             })
             .instruction()
@@ -199,31 +262,86 @@ describe("flashloan", () => {
     poolTokenAccount = await spl_token.account.token.fetch(poolToken);
     expect(poolTokenAccount.amount.toNumber()).to.be.equal(1000.1 * web3.LAMPORTS_PER_SOL);
 
-
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.borrowing).to.be.false;
+    expect(poolAccount.loanAmount.toNumber()).to.be.equal(0);
   });
 
-  it("Should borrow and repay with discount", async() => {
+  it("Should fail to repay without fee", async () => {
     const pool = await find_pool(flashloan.publicKey, mint.publicKey);
-    const [tokenAuthority, _nonce] = await find_token_authority(flashloan.publicKey);
-    const poolToken = await find_pool_token(flashloan.publicKey, mint.publicKey);
-
-    let poolTokenAccount = await spl_token.account.token.fetch(poolToken);
-    const poolTokenAmountBefore = poolTokenAccount.amount;
 
-    const voucher = Keypair.generate();
-    await program.methods.mintVoucher()
+    await expect(program.methods
+      .borrow(new BN(100 * web3.LAMPORTS_PER_SOL), null)
       .accounts({
         flashloan: flashloan.publicKey,
-        authority: authority.publicKey,
         pool,
-        voucher: voucher.publicKey,
-        payer: provider.wallet.publicKey,
+        userToken: token2.publicKey,
+        borrower: provider.wallet.publicKey,
+        instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
       })
-      .signers([authority, voucher])
+      .postInstructions(
+        [
+          await program.methods
+            .repay(new BN(100 * web3.LAMPORTS_PER_SOL))
+            .accounts({
+              flashloan: flashloan.publicKey,
+              pool,
+              userToken: token2.publicKey,
+              borrower: provider.wallet.publicKey,
+              instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+            })
+            .instruction()
+        ]
+      )
+      .rpc()).to.be.rejectedWith(/IncorrectRepay/);
+  });
+
+  it("Should borrow and repay many", async () => {
+    const mint2 = Keypair.generate();
+    const token3 = Keypair.generate();
+    const token4 = Keypair.generate();
+    const lp_token3 = Keypair.generate();
+
+    await create_mint(mint2, provider.wallet.publicKey);
+    await create_token(token3, mint2.publicKey, provider.wallet.publicKey);
+    await create_token(token4, mint2.publicKey, provider.wallet.publicKey);
+
+    await spl_token.methods
+      .mintTo(new BN(1000.1 * web3.LAMPORTS_PER_SOL))
+      .accounts(
+        {
+          mint: mint2.publicKey,
+          to: token3.publicKey,
+          authority: provider.wallet.publicKey,
+        })
       .rpc();
 
-    const voucherAccount = await program.account.voucher.fetch(voucher.publicKey);
-    expect(voucherAccount.pool).to.be.deep.equal(pool);
+    await program.methods
+      .addPool(new BN(1_000_000), new BN(500_000))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        poolAdmin: authority.publicKey,
+        tokenMint: mint2.publicKey,
+      })
+      .signers([authority])
+      .rpc();
+
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    const pool2 = await find_pool(flashloan.publicKey, mint2.publicKey);
+    const poolToken = await find_pool_token(flashloan.publicKey, mint.publicKey);
+    const poolToken2 = await find_pool_token(flashloan.publicKey, mint2.publicKey);
+    await create_token(lp_token3, await find_lp_token_mint(flashloan.publicKey, mint2.publicKey), provider.wallet.publicKey);
+
+    await program.methods
+      .deposit(new BN(1000 * web3.LAMPORTS_PER_SOL), new BN(1000 * web3.LAMPORTS_PER_SOL))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pool: pool2,
+        depositor: provider.wallet.publicKey,
+        userToken: token3.publicKey,
+        userLpToken: lp_token3.publicKey,
+      })
+      .rpc();
 
     await spl_token.methods
       .mintTo(new BN(0.1 * web3.LAMPORTS_PER_SOL))
@@ -235,20 +353,172 @@ describe("flashloan", () => {
         })
       .rpc();
 
+    await spl_token.methods
+      .transfer(new BN(0.1 * web3.LAMPORTS_PER_SOL))
+      .accounts({
+        source: token3.publicKey,
+        destination: token4.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const poolTokenAmountBefore = (await spl_token.account.token.fetch(poolToken)).amount;
+    const legs = [
+      { pubkey: pool, isSigner: false, isWritable: true },
+      { pubkey: poolToken, isSigner: false, isWritable: true },
+      { pubkey: token2.publicKey, isSigner: false, isWritable: true },
+      { pubkey: pool2, isSigner: false, isWritable: true },
+      { pubkey: poolToken2, isSigner: false, isWritable: true },
+      { pubkey: token4.publicKey, isSigner: false, isWritable: true },
+    ];
+
+    await program.methods
+      .borrowMany([new BN(100 * web3.LAMPORTS_PER_SOL), new BN(50 * web3.LAMPORTS_PER_SOL)])
+      .accounts({
+        flashloan: flashloan.publicKey,
+        borrower: provider.wallet.publicKey,
+        instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+      })
+      .remainingAccounts(legs)
+      .postInstructions(
+        [
+          await program.methods
+            .repayMany([new BN(100.1 * web3.LAMPORTS_PER_SOL), new BN(50.05 * web3.LAMPORTS_PER_SOL)])
+            .accounts({
+              flashloan: flashloan.publicKey,
+              borrower: provider.wallet.publicKey,
+              instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+            })
+            .remainingAccounts(legs)
+            .instruction()
+        ]
+      )
+      .rpc();
+
+    const poolTokenAccount = await spl_token.account.token.fetch(poolToken);
+    const poolTokenAccount2 = await spl_token.account.token.fetch(poolToken2);
+    expect(poolTokenAccount.amount.sub(poolTokenAmountBefore).toNumber()).to.be.equal(0.1 * web3.LAMPORTS_PER_SOL);
+    expect(poolTokenAccount2.amount.toNumber()).to.be.equal(1000.05 * web3.LAMPORTS_PER_SOL);
+
+    expect((await program.account.pool.fetch(pool)).borrowing).to.be.false;
+    expect((await program.account.pool.fetch(pool2)).borrowing).to.be.false;
+  });
+
+  it("Should borrow and repay with discount", async() => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+
+    const voucher = await mint_voucher(pool, null, null, null);
+
+    const voucherAccount = await program.account.voucher.fetch(voucher);
+    expect(voucherAccount.pool).to.be.deep.equal(pool);
+    expect(voucherAccount.beneficiary).to.be.deep.equal(provider.wallet.publicKey);
+    expect(voucherAccount.fee).to.be.null;
+
+    await top_up_fees();
+    await borrow_with_voucher(pool, voucher, 100.05);
+
+    await revoke_voucher(pool, voucher);
+  });
+
+  it("Should borrow and repay with a partner voucher rate", async() => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+
+    const voucher = await mint_voucher(pool, new BN(200_000), null, null);
+
+    const voucherAccount = await program.account.voucher.fetch(voucher);
+    expect(voucherAccount.fee.rate.toNumber()).to.be.equal(200_000);
+
+    // 2 bps instead of the pool discounted fee
+    await top_up_fees();
+    await borrow_with_voucher(pool, voucher, 100.02);
+
+    await revoke_voucher(pool, voucher);
+  });
+
+  it("Should enforce voucher limits and revoke vouchers", async() => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    await top_up_fees();
+
+    const expired = await mint_voucher(pool, null, new BN(1), null);
+    await expect(borrow_with_voucher(pool, expired, 100.05)).to.be.rejectedWith(/VoucherExpired/);
+    await revoke_voucher(pool, expired);
+
+    expect(await provider.connection.getAccountInfo(expired)).to.be.null;
+
+    const single = await mint_voucher(pool, null, null, new BN(1));
+    await borrow_with_voucher(pool, single, 100.05);
+
+    const voucherAccount = await program.account.voucher.fetch(single);
+    expect(voucherAccount.remainingUses.toNumber()).to.be.equal(0);
+
+    await expect(borrow_with_voucher(pool, single, 100.05)).to.be.rejectedWith(/VoucherExhausted/);
+    await revoke_voucher(pool, single);
+  });
+
+  it("Should not charge a voucher that doesn't lower the fee", async() => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    await top_up_fees();
+
+    // same rate as the pool fee, the loan costs the same without it
+    const voucher = await mint_voucher(pool, new BN(1_000_000), null, new BN(1));
+    await borrow_with_voucher(pool, voucher, 100.1);
+
+    const voucherAccount = await program.account.voucher.fetch(voucher);
+    expect(voucherAccount.remainingUses.toNumber()).to.be.equal(1);
+
+    await revoke_voucher(pool, voucher);
+  });
+
+  it("Should borrow and repay with an nft voucher", async() => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    const voucherMint = Keypair.generate();
+    const recipientToken = Keypair.generate();
+    const [voucher, _nonce] = await PublicKey.findProgramAddress(
+      [pool.toBuffer(), Buffer.from(anchor.utils.bytes.utf8.encode("voucher")), voucherMint.publicKey.toBuffer()],
+      program.programId
+    );
+    const [token_authority, _bump] = await find_token_authority(flashloan.publicKey);
+
+    await program.methods.mintNftVoucher(null, null, null, null)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        tokenAuthority: token_authority,
+        voucherIssuer: authority.publicKey,
+        pool,
+        voucher,
+        voucherMint: voucherMint.publicKey,
+        recipientToken: recipientToken.publicKey,
+        recipient: provider.wallet.publicKey,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([authority, voucherMint, recipientToken])
+      .rpc();
+
+    const voucherMintAccount = await spl_token.account.mint.fetch(voucherMint.publicKey);
+    expect(voucherMintAccount.supply.toNumber()).to.be.equal(1);
+    expect(voucherMintAccount.mintAuthority).to.be.null;
+
+    await top_up_fees();
+
     const borrowInstruction = await program.methods
-      .borrow(new BN(100 * web3.LAMPORTS_PER_SOL))
+      .borrow(new BN(100 * web3.LAMPORTS_PER_SOL), null)
       .accounts({
         flashloan: flashloan.publicKey,
         pool,
         userToken: token2.publicKey,
+        borrower: provider.wallet.publicKey,
         instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
-      }).signers([voucher]).instruction();
+      }).instruction();
 
-    // Add voucher account
+    // Add voucher and the token account holding it
     borrowInstruction.keys.push({
-      isSigner: true,
+      isSigner: false,
+      isWritable: false,
+      pubkey: voucher,
+    }, {
+      isSigner: false,
       isWritable: false,
-      pubkey: voucher.publicKey,
+      pubkey: recipientToken.publicKey,
     })
 
     await program.methods
@@ -257,21 +527,290 @@ describe("flashloan", () => {
         flashloan: flashloan.publicKey,
         pool,
         userToken: token2.publicKey,
+        borrower: provider.wallet.publicKey,
+        instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+      })
+      .preInstructions([borrowInstruction])
+      .rpc();
+  });
+
+  it("Should borrow and repay with an allowlist rate", async() => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    const accounts = {
+      flashloan: flashloan.publicKey,
+      feeManager: authority.publicKey,
+      pool,
+    };
+
+    // single entry allowlist, the root is the leaf of the wallet at 2 bps
+    const fee = new BN(200_000);
+    const leaf = keccak_256.array(Buffer.concat([
+      Buffer.from([0]),
+      provider.wallet.publicKey.toBuffer(),
+      fee.toArrayLike(Buffer, "le", 8),
+    ]));
+
+    await program.methods
+      .setAllowlistRoot(leaf)
+      .accounts(accounts)
+      .signers([authority])
+      .rpc();
+
+    await top_up_fees();
+
+    const borrow = async (proof) => program.methods
+      .borrow(new BN(100 * web3.LAMPORTS_PER_SOL), proof)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pool,
+        userToken: token2.publicKey,
+        borrower: provider.wallet.publicKey,
+        instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+      })
+      .postInstructions(
+        [
+          await program.methods
+            .repay(new BN(100.02 * web3.LAMPORTS_PER_SOL))
+            .accounts({
+              flashloan: flashloan.publicKey,
+              pool,
+              userToken: token2.publicKey,
+              borrower: provider.wallet.publicKey,
+              instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+            })
+            .instruction()
+        ]
+      )
+      .rpc();
+
+    await expect(borrow({ fee: new BN(100_000), proof: [] })).to.be.rejectedWith(/InvalidAllowlistProof/);
+    await borrow({ fee, proof: [] });
+
+    await program.methods
+      .setAllowlistRoot(null)
+      .accounts(accounts)
+      .signers([authority])
+      .rpc();
+  });
+
+  it("Should borrow and repay with a governance token discount", async() => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    const accounts = {
+      flashloan: flashloan.publicKey,
+      feeManager: authority.publicKey,
+      pool,
+    };
+
+    const governanceMint = Keypair.generate();
+    const governanceToken = Keypair.generate();
+    await create_mint(governanceMint, provider.wallet.publicKey, provider.wallet.publicKey);
+    await create_token(governanceToken, governanceMint.publicKey, provider.wallet.publicKey);
+    await spl_token.methods
+      .mintTo(new BN(1_000))
+      .accounts(
+        {
+          mint: governanceMint.publicKey,
+          to: governanceToken.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+      .rpc();
+
+    // 2 bps from 1000 governance tokens
+    await program.methods
+      .setGovernanceDiscount({
+        mint: governanceMint.publicKey,
+        tiers: [
+          { minAmount: new BN(100), fee: new BN(400_000) },
+          { minAmount: new BN(1_000), fee: new BN(200_000) },
+        ],
+      })
+      .accounts(accounts)
+      .signers([authority])
+      .rpc();
+
+    await top_up_fees();
+
+    const borrowWithDiscount = async () => program.methods
+      .borrow(new BN(100 * web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pool,
+        userToken: token2.publicKey,
+        borrower: provider.wallet.publicKey,
+        instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+      })
+      .remainingAccounts([{ pubkey: governanceToken.publicKey, isSigner: false, isWritable: false }])
+      .postInstructions(
+        [
+          await program.methods
+            .repay(new BN(100.02 * web3.LAMPORTS_PER_SOL))
+            .accounts({
+              flashloan: flashloan.publicKey,
+              pool,
+              userToken: token2.publicKey,
+              borrower: provider.wallet.publicKey,
+              instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+            })
+            .instruction()
+        ]
+      )
+      .rpc();
+
+    // only locked balances count
+    await expect(borrowWithDiscount()).to.be.rejectedWith(/IncorrectRepay/);
+
+    await spl_token.methods
+      .freezeAccount()
+      .accounts({
+        account: governanceToken.publicKey,
+        mint: governanceMint.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    await borrowWithDiscount();
+
+    await program.methods
+      .setGovernanceDiscount(null)
+      .accounts(accounts)
+      .signers([authority])
+      .rpc();
+  });
+
+  it("Should reject new loans while paused", async () => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+
+    const setPaused = (paused: boolean) => program.methods
+      .setPaused(paused)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pauser: authority.publicKey,
+      })
+      .signers([authority])
+      .rpc();
+
+    await setPaused(true);
+
+    await expect(program.methods
+      .borrow(new BN(100 * web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pool,
+        userToken: token2.publicKey,
+        borrower: provider.wallet.publicKey,
+        instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+      })
+      .rpc()).to.be.rejectedWith(/Paused/);
+
+    await setPaused(false);
+  });
+
+  it("Should accrue and collect protocol fees", async () => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    const poolToken = await find_pool_token(flashloan.publicKey, mint.publicKey);
+    const treasuryToken = Keypair.generate();
+    await create_token(treasuryToken, mint.publicKey, provider.wallet.publicKey);
+
+    await program.methods
+      .setProtocolFee(new BN(500_000_000), provider.wallet.publicKey)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        authority: authority.publicKey,
+      })
+      .signers([authority])
+      .rpc();
+
+    await spl_token.methods
+      .mintTo(new BN(0.1 * web3.LAMPORTS_PER_SOL))
+      .accounts(
+        {
+          mint: mint.publicKey,
+          to: token2.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+      .rpc();
+
+    await program.methods
+      .borrow(new BN(100 * web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pool,
+        userToken: token2.publicKey,
+        borrower: provider.wallet.publicKey,
         instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
       })
-      .preInstructions(
+      .postInstructions(
         [
-          await spl_token.methods
-            .approve(new BN(101 * web3.LAMPORTS_PER_SOL))
+          await program.methods
+            .repay(new BN(100.1 * web3.LAMPORTS_PER_SOL))
             .accounts({
-              source: token2.publicKey,
-              delegate: tokenAuthority,
-              authority: provider.wallet.publicKey,
-            }).instruction(),
-          borrowInstruction,
+              flashloan: flashloan.publicKey,
+              pool,
+              userToken: token2.publicKey,
+              borrower: provider.wallet.publicKey,
+              instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+            })
+            .instruction()
         ]
       )
-      .signers([voucher])
       .rpc();
+
+    let poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.protocolFees.toNumber()).to.be.equal(0.05 * web3.LAMPORTS_PER_SOL);
+
+    const poolTokenAmountBefore = (await spl_token.account.token.fetch(poolToken)).amount;
+
+    await program.methods
+      .collectProtocolFees()
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pool,
+        treasuryToken: treasuryToken.publicKey,
+      })
+      .rpc();
+
+    poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.protocolFees.toNumber()).to.be.equal(0);
+
+    const treasuryTokenAccount = await spl_token.account.token.fetch(treasuryToken.publicKey);
+    expect(treasuryTokenAccount.amount.toNumber()).to.be.equal(0.05 * web3.LAMPORTS_PER_SOL);
+
+    const poolTokenAccount = await spl_token.account.token.fetch(poolToken);
+    expect(poolTokenAmountBefore.sub(poolTokenAccount.amount).toNumber()).to.be.equal(0.05 * web3.LAMPORTS_PER_SOL);
+  });
+
+  it("Should lend to a flash loan receiver", async () => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    const poolToken = await find_pool_token(flashloan.publicKey, mint.publicKey);
+    await top_up_fees();
+
+    // the receiver repays unless it is passed data
+    const flashLoan = (data: Buffer) => program.methods
+      .flashLoan(new BN(100 * web3.LAMPORTS_PER_SOL), data)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pool,
+        receiverToken: token2.publicKey,
+        receiverProgram: receiver.programId,
+        initiator: provider.wallet.publicKey,
+      })
+      .remainingAccounts([
+        { pubkey: token2.publicKey, isSigner: false, isWritable: true },
+        { pubkey: poolToken, isSigner: false, isWritable: true },
+        { pubkey: provider.wallet.publicKey, isSigner: true, isWritable: false },
+        { pubkey: spl_token.programId, isSigner: false, isWritable: false },
+      ])
+      .rpc();
+
+    const poolTokenAmountBefore = (await spl_token.account.token.fetch(poolToken)).amount;
+
+    await flashLoan(Buffer.from([]));
+
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.borrowing).to.be.false;
+    const poolTokenAccount = await spl_token.account.token.fetch(poolToken);
+    expect(poolTokenAccount.amount.gt(poolTokenAmountBefore)).to.be.true;
+
+    await expect(flashLoan(Buffer.from([1]))).to.be.rejectedWith(/IncorrectRepay/);
   });
 });
@@ -0,0 +1,77 @@
+import * as anchor from "@project-serum/anchor";
+import { Program, web3 } from "@project-serum/anchor";
+import { PublicKey } from '@solana/web3.js';
+import { Flashloan } from "../target/types/flashloan";
+
+import { expect } from 'chai';
+import * as chai from 'chai';
+import chaiAsPromised from 'chai-as-promised';
+chai.use(chaiAsPromised);
+
+// Accounts in the original layout, loaded by the validator from tests/fixtures, see Anchor.toml
+const LEGACY_FLASHLOAN = new PublicKey("BNgV1HgXBuJigFWE56dQ43aq8cyvu9uZstShp1fDhuXT");
+const LEGACY_AUTHORITY = new PublicKey("46kXjyjFM31qJwqprv4aWV3b9dWVmbwMSbr9GUcr9i3C");
+// pool of LEGACY_FLASHLOAN for LEGACY_MINT, 10 and 5 basis points fees
+const LEGACY_POOL = new PublicKey("4tRQ1s1idzmvDXsz9s7JHtvSjcY5tQsUVtpyoUVAipVn");
+const LEGACY_MINT = new PublicKey("y5m5b6Hsak1pmi9Q32VxPLydPjye9LDeLxHLsq63NHU");
+
+describe("migration", () => {
+  // Configure the client to use the local cluster.
+  anchor.setProvider(anchor.Provider.env());
+  const provider = anchor.getProvider();
+  const program = anchor.workspace.Flashloan as Program<Flashloan>;
+
+  const migrateFlashloan = () => program.methods
+    .migrateFlashloan()
+    .accounts({
+      flashloan: LEGACY_FLASHLOAN,
+      payer: provider.wallet.publicKey,
+      systemProgram: web3.SystemProgram.programId,
+    })
+    .rpc();
+
+  const migratePool = () => program.methods
+    .migratePool()
+    .accounts({
+      flashloan: LEGACY_FLASHLOAN,
+      pool: LEGACY_POOL,
+      payer: provider.wallet.publicKey,
+      systemProgram: web3.SystemProgram.programId,
+    })
+    .rpc();
+
+  it("Should grow and convert a legacy flashloan", async () => {
+    expect((await provider.connection.getAccountInfo(LEGACY_FLASHLOAN)).data.length).to.be.equal(41);
+
+    await migrateFlashloan();
+
+    // the runtime kept the length written by the program
+    const info = await provider.connection.getAccountInfo(LEGACY_FLASHLOAN);
+    expect(info.data.length).to.be.greaterThan(41);
+    expect(info.lamports).to.be.equal(await provider.connection.getMinimumBalanceForRentExemption(info.data.length));
+
+    const flashloanAccount = await program.account.flashLoan.fetch(LEGACY_FLASHLOAN);
+    expect(flashloanAccount.authority).to.be.deep.equal(LEGACY_AUTHORITY);
+    expect(flashloanAccount.trustedCallers).to.be.empty;
+
+    await expect(migrateFlashloan()).to.be.rejectedWith(/AlreadyMigrated/);
+  });
+
+  it("Should grow and convert a legacy pool", async () => {
+    expect((await provider.connection.getAccountInfo(LEGACY_POOL)).data.length).to.be.equal(114);
+
+    await migratePool();
+
+    const info = await provider.connection.getAccountInfo(LEGACY_POOL);
+    expect(info.data.length).to.be.greaterThan(114);
+
+    const poolAccount = await program.account.pool.fetch(LEGACY_POOL);
+    expect(poolAccount.tokenMint).to.be.deep.equal(LEGACY_MINT);
+    expect(poolAccount.fee.rate.toNumber()).to.be.equal(1_000_000);
+    expect(poolAccount.discountedFee.rate.toNumber()).to.be.equal(500_000);
+    expect(poolAccount.borrowing).to.be.false;
+    expect(poolAccount.loanAmount.toNumber()).to.be.equal(0);
+
+    await expect(migratePool()).to.be.rejectedWith(/AlreadyMigrated/);
+  });
+});
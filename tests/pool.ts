@@ -4,7 +4,9 @@ import {PublicKey, Keypair} from '@solana/web3.js';
 import { Flashloan } from "../target/types/flashloan";
 
 import { expect } from 'chai';
-
+import * as chai from 'chai';
+import chaiAsPromised from 'chai-as-promised';
+chai.use(chaiAsPromised);
 
 describe("pool", () => {
   // Configure the client to use the local cluster.
@@ -87,7 +89,7 @@ describe("pool", () => {
 
   it("Should initialize flashloan", async () => {
     await program.methods
-      .initialize()
+      .initialize(new BN(10_000_000))
       .accounts({
         flashloan: flashloan.publicKey,
         authority: authority.publicKey,
@@ -97,17 +99,46 @@ describe("pool", () => {
 
     const flashloanAccount = await program.account.flashLoan.fetch(flashloan.publicKey);
     expect(flashloanAccount.authority).to.be.deep.equal(authority.publicKey);
+    expect(flashloanAccount.maxFee.rate.toNumber()).to.be.equal(10_000_000);
 
     const [token_authority, bump] = await find_token_authority(flashloan.publicKey);
     expect(flashloanAccount.tokenAuthorityBump).to.be.equal(bump);
   });
 
-  it("Should add pool", async () => {
+  it("Should add and remove trusted caller", async () => {
+    const router = Keypair.generate().publicKey;
+
+    await program.methods
+      .addTrustedCaller(router)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        authority: authority.publicKey,
+      })
+      .signers([authority])
+      .rpc();
+
+    let flashloanAccount = await program.account.flashLoan.fetch(flashloan.publicKey);
+    expect(flashloanAccount.trustedCallers).to.be.deep.equal([router]);
+
     await program.methods
-      .addPool(10, 5)
+      .removeTrustedCaller(router)
       .accounts({
         flashloan: flashloan.publicKey,
         authority: authority.publicKey,
+      })
+      .signers([authority])
+      .rpc();
+
+    flashloanAccount = await program.account.flashLoan.fetch(flashloan.publicKey);
+    expect(flashloanAccount.trustedCallers).to.be.empty;
+  });
+
+  it("Should add pool", async () => {
+    await program.methods
+      .addPool(new BN(1_000_000), new BN(500_000))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        poolAdmin: authority.publicKey,
         tokenMint: mint.publicKey,
       })
       .signers([authority])
@@ -134,6 +165,276 @@ describe("pool", () => {
     expect(lpTokenMintAccount.decimals).to.be.equal(mintAccount.decimals);
   });
 
+  it("Should update pool", async () => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+
+    await program.methods
+      .updatePool(new BN(2_000_000), new BN(1_000_000), new BN(1000))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        feeManager: authority.publicKey,
+        pool,
+      })
+      .signers([authority])
+      .rpc();
+
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.fee.rate.toNumber()).to.be.equal(2_000_000);
+    expect(poolAccount.discountedFee.rate.toNumber()).to.be.equal(1_000_000);
+    expect(poolAccount.minFee.toNumber()).to.be.equal(1000);
+
+    await expect(program.methods
+      .updatePool(new BN(1_000_000), new BN(2_000_000), new BN(0))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        feeManager: authority.publicKey,
+        pool,
+      })
+      .signers([authority])
+      .rpc()).to.be.rejectedWith(/DiscountedFeeAboveFee/);
+
+    await expect(program.methods
+      .updatePool(new BN(20_000_000), new BN(1_000_000), new BN(0))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        feeManager: authority.publicKey,
+        pool,
+      })
+      .signers([authority])
+      .rpc()).to.be.rejectedWith(/FeeAboveMax/);
+
+    await expect(program.methods
+      .updatePool(new BN(2_000_000), new BN(1_000_000), new BN(1_000_001))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        feeManager: authority.publicKey,
+        pool,
+      })
+      .signers([authority])
+      .rpc()).to.be.rejectedWith(/MinFeeTooHigh/);
+  });
+
+  it("Should set fee curve", async () => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    const accounts = {
+      flashloan: flashloan.publicKey,
+      feeManager: authority.publicKey,
+      pool,
+    };
+
+    // 0.5 bps up to half the vault, 50.5 bps for all of it
+    await program.methods
+      .setFeeCurve({ baseFee: new BN(50_000), kink: new BN(500_000_000), slope: new BN(10_000_000) })
+      .accounts(accounts)
+      .signers([authority])
+      .rpc();
+
+    let poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.feeCurve.baseFee.toNumber()).to.be.equal(50_000);
+    expect(poolAccount.feeCurve.kink.toNumber()).to.be.equal(500_000_000);
+    expect(poolAccount.feeCurve.slope.toNumber()).to.be.equal(10_000_000);
+
+    await expect(program.methods
+      .setFeeCurve({ baseFee: new BN(1_000_000), kink: new BN(500_000_000), slope: new BN(20_000_000) })
+      .accounts(accounts)
+      .signers([authority])
+      .rpc()).to.be.rejectedWith(/FeeAboveMax/);
+
+    await expect(program.methods
+      .setFeeCurve({ baseFee: new BN(0), kink: new BN(2_000_000_000), slope: new BN(0) })
+      .accounts(accounts)
+      .signers([authority])
+      .rpc()).to.be.rejectedWith(/InvalidFeeCurve/);
+
+    await program.methods
+      .setFeeCurve(null)
+      .accounts(accounts)
+      .signers([authority])
+      .rpc();
+
+    poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.feeCurve).to.be.null;
+  });
+
+  it("Should set fee tiers", async () => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    const accounts = {
+      flashloan: flashloan.publicKey,
+      feeManager: authority.publicKey,
+      pool,
+    };
+
+    await program.methods
+      .setFeeTiers([
+        { minAmount: new BN(1_000_000), fee: new BN(500_000) },
+        { minAmount: new BN(100_000_000), fee: new BN(100_000) },
+      ])
+      .accounts(accounts)
+      .signers([authority])
+      .rpc();
+
+    let poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.feeTiers.length).to.be.equal(2);
+    expect(poolAccount.feeTiers[1].minAmount.toNumber()).to.be.equal(100_000_000);
+    expect(poolAccount.feeTiers[1].fee.toNumber()).to.be.equal(100_000);
+
+    await expect(program.methods
+      .setFeeTiers([
+        { minAmount: new BN(100_000_000), fee: new BN(100_000) },
+        { minAmount: new BN(1_000_000), fee: new BN(500_000) },
+      ])
+      .accounts(accounts)
+      .signers([authority])
+      .rpc()).to.be.rejectedWith(/InvalidFeeTiers/);
+
+    await program.methods
+      .setFeeTiers([])
+      .accounts(accounts)
+      .signers([authority])
+      .rpc();
+
+    poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.feeTiers).to.be.empty;
+  });
+
+  it("Should transfer authority in two steps", async () => {
+    const newAuthority = Keypair.generate();
+
+    const accept = (signer: Keypair) => program.methods
+      .acceptAuthority()
+      .accounts({
+        flashloan: flashloan.publicKey,
+        newAuthority: signer.publicKey,
+      })
+      .signers([signer])
+      .rpc();
+
+    await program.methods
+      .proposeAuthority(newAuthority.publicKey)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        authority: authority.publicKey,
+      })
+      .signers([authority])
+      .rpc();
+
+    let flashloanAccount = await program.account.flashLoan.fetch(flashloan.publicKey);
+    expect(flashloanAccount.authority).to.be.deep.equal(authority.publicKey);
+    expect(flashloanAccount.pendingAuthority).to.be.deep.equal(newAuthority.publicKey);
+
+    await expect(accept(Keypair.generate())).to.be.rejectedWith(/NotPendingAuthority/);
+    await accept(newAuthority);
+
+    flashloanAccount = await program.account.flashLoan.fetch(flashloan.publicKey);
+    expect(flashloanAccount.authority).to.be.deep.equal(newAuthority.publicKey);
+    expect(flashloanAccount.pendingAuthority).to.be.null;
+
+    // hand control back for the following tests
+    await program.methods
+      .proposeAuthority(authority.publicKey)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        authority: newAuthority.publicKey,
+      })
+      .signers([newAuthority])
+      .rpc();
+    await accept(authority);
+  });
+
+  it("Should delegate roles", async () => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    const ops = Keypair.generate();
+
+    const setRole = (role, member: PublicKey) => program.methods
+      .setRole(role, member)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        authority: authority.publicKey,
+      })
+      .signers([authority])
+      .rpc();
+
+    const updatePool = () => program.methods
+      .updatePool(new BN(2_000_000), new BN(1_000_000), new BN(1000))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        feeManager: ops.publicKey,
+        pool,
+      })
+      .signers([ops])
+      .rpc();
+
+    const setPaused = (paused: boolean) => program.methods
+      .setPaused(paused)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pauser: ops.publicKey,
+      })
+      .signers([ops])
+      .rpc();
+
+    await expect(updatePool()).to.be.rejectedWith(/MissingRole/);
+    await setRole({ feeManager: {} }, ops.publicKey);
+    await updatePool();
+    await expect(setPaused(true)).to.be.rejectedWith(/MissingRole/);
+
+    await setRole({ pauser: {} }, ops.publicKey);
+    await setPaused(true);
+    let flashloanAccount = await program.account.flashLoan.fetch(flashloan.publicKey);
+    expect(flashloanAccount.feeManager).to.be.deep.equal(ops.publicKey);
+    expect(flashloanAccount.pauser).to.be.deep.equal(ops.publicKey);
+    expect(flashloanAccount.paused).to.be.true;
+
+    await setPaused(false);
+    await setRole({ feeManager: {} }, PublicKey.default);
+    await setRole({ pauser: {} }, PublicKey.default);
+    flashloanAccount = await program.account.flashLoan.fetch(flashloan.publicKey);
+    expect(flashloanAccount.paused).to.be.false;
+  });
+
+  it("Should require the multisig threshold", async () => {
+    const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    const keys = [Keypair.generate(), Keypair.generate(), Keypair.generate()];
+
+    // the first key signs as the named admin account, the others as remaining accounts
+    const cosigners = (signers: Keypair[]) => signers.slice(1)
+      .map((key) => ({ pubkey: key.publicKey, isSigner: true, isWritable: false }));
+
+    const setMultisig = (multisig, signers: Keypair[]) => program.methods
+      .setMultisig(multisig)
+      .accounts({
+        flashloan: flashloan.publicKey,
+        authority: signers[0].publicKey,
+      })
+      .remainingAccounts(cosigners(signers))
+      .signers(signers)
+      .rpc();
+
+    const updatePool = (signers: Keypair[]) => program.methods
+      .updatePool(new BN(2_000_000), new BN(1_000_000), new BN(1000))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        feeManager: signers[0].publicKey,
+        pool,
+      })
+      .remainingAccounts(cosigners(signers))
+      .signers(signers)
+      .rpc();
+
+    await expect(setMultisig({ signers: keys.map((key) => key.publicKey), threshold: 4 }, [authority]))
+      .to.be.rejectedWith(/InvalidMultisig/);
+    await setMultisig({ signers: keys.map((key) => key.publicKey), threshold: 2 }, [authority]);
+
+    await expect(updatePool([authority])).to.be.rejectedWith(/MissingRole/);
+    await expect(updatePool([keys[0]])).to.be.rejectedWith(/MissingRole/);
+    await updatePool([keys[0], keys[2]]);
+
+    await setMultisig(null, [keys[1], keys[2]]);
+    const flashloanAccount = await program.account.flashLoan.fetch(flashloan.publicKey);
+    expect(flashloanAccount.multisig).to.be.null;
+    await updatePool([authority]);
+  });
+
   it("Should add liquidity", async () => {
     await spl_token.methods
       .mintTo(new BN(1000000))
@@ -145,7 +446,6 @@ describe("pool", () => {
         })
       .rpc();
 
-    const [tokenAuthority, _nonce] = await find_token_authority(flashloan.publicKey);
     const lpTokenMint = await find_lp_token_mint(flashloan.publicKey, mint.publicKey)
     await create_token(lp_token1, lpTokenMint, provider.wallet.publicKey);
 
@@ -153,25 +453,26 @@ describe("pool", () => {
     expect(tokenAccount.amount.toNumber()).to.be.equal(1000000);
 
     const pool = await find_pool(flashloan.publicKey, mint.publicKey);
+    await expect(program.methods
+      .deposit(new BN(1000000), new BN(1000001))
+      .accounts({
+        flashloan: flashloan.publicKey,
+        pool,
+        depositor: provider.wallet.publicKey,
+        userToken: token1.publicKey,
+        userLpToken: lp_token1.publicKey,
+      })
+      .rpc()).to.be.rejectedWith(/SlippageExceeded/);
+
     await program.methods
-      .deposit(new BN(1000000))
+      .deposit(new BN(1000000), new BN(1000000))
       .accounts({
         flashloan: flashloan.publicKey,
         pool,
+        depositor: provider.wallet.publicKey,
         userToken: token1.publicKey,
         userLpToken: lp_token1.publicKey,
       })
-      .preInstructions(
-        [
-          await spl_token.methods
-            .approve(new BN(1000000))
-            .accounts({
-              source: token1.publicKey,
-              delegate: tokenAuthority,
-              authority: provider.wallet.publicKey
-            }).instruction()
-        ]
-      )
       .rpc();
 
     const poolAccount = await program.account.pool.fetch(pool);
@@ -183,7 +484,6 @@ describe("pool", () => {
   })
 
   it("Should remove liquidity", async () => {
-    const [tokenAuthority, _nonce] = await find_token_authority(flashloan.publicKey);
     const pool = await find_pool(flashloan.publicKey, mint.publicKey);
 
     let lpTokenAccount = await spl_token.account.token.fetch(lp_token1.publicKey);
@@ -191,28 +491,25 @@ describe("pool", () => {
     expect(lpTokenAccount.amount.toNumber()).to.be.equal(1000000);
     expect(tokenAccount.amount.toNumber()).to.be.equal(0);
 
-    await program.methods
-      .withdraw(new BN(1000000))
+    const withdraw = (userToken: PublicKey) => program.methods
+      .withdraw(new BN(1000000), new BN(1000000))
       .accounts(
         {
           flashloan: flashloan.publicKey,
           pool,
-          userToken: token1.publicKey,
+          withdrawer: provider.wallet.publicKey,
+          userToken,
           userLpToken: lp_token1.publicKey,
         })
-      .preInstructions(
-        [
-          await spl_token.methods
-            .approve(new BN(1000000))
-            .accounts({
-              source: lp_token1.publicKey,
-              delegate: tokenAuthority,
-              authority: provider.wallet.publicKey
-            }).instruction()
-        ]
-      )
       .rpc();
 
+    // the tokens only go to an account of the lp token owner
+    const otherToken = Keypair.generate();
+    await create_token(otherToken, mint.publicKey, Keypair.generate().publicKey);
+    await expect(withdraw(otherToken.publicKey)).to.be.rejectedWith(/ConstraintRaw/);
+
+    await withdraw(token1.publicKey);
+
     lpTokenAccount = await spl_token.account.token.fetch(lp_token1.publicKey);
     tokenAccount = await spl_token.account.token.fetch(token1.publicKey);
     expect(lpTokenAccount.amount.toNumber()).to.be.equal(0);